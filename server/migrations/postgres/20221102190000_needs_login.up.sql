@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN needs_login BOOLEAN NOT NULL DEFAULT FALSE;
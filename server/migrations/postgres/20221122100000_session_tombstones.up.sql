@@ -0,0 +1,5 @@
+-- Add up migration script here
+CREATE TABLE session_tombstones (
+	user_id VARCHAR(20) PRIMARY KEY,
+	session_epoch INTEGER NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE users DROP COLUMN discriminator;
+ALTER TABLE users DROP COLUMN global_name;
+UPDATE users SET avatar = '' WHERE avatar IS NULL;
+ALTER TABLE users ALTER COLUMN avatar SET NOT NULL;
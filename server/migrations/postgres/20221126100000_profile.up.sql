@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE users ALTER COLUMN avatar DROP NOT NULL;
+ALTER TABLE users ADD COLUMN global_name VARCHAR(32) NULL;
+ALTER TABLE users ADD COLUMN discriminator VARCHAR(4) NULL;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE bans (
+	user_id VARCHAR(20) PRIMARY KEY,
+	reason TEXT NULL,
+	banned_by VARCHAR(20) NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE admin_audit (
+	id BIGSERIAL PRIMARY KEY,
+	admin_id VARCHAR(20) NOT NULL,
+	action VARCHAR(32) NOT NULL,
+	target VARCHAR(20) NULL,
+	detail TEXT NULL,
+	outcome VARCHAR(16) NOT NULL DEFAULT 'done',
+	created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN session_epoch INTEGER NOT NULL DEFAULT 0;
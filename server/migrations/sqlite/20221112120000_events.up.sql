@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE events (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	target VARCHAR(20) NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	source VARCHAR(20) NULL,
+	guild_id VARCHAR(20) NULL,
+	channel_id VARCHAR(20) NULL,
+	kind VARCHAR(16) NOT NULL,
+	detail TEXT NULL,
+	power_delta REAL NOT NULL,
+	power REAL NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX events_target ON events (target, id);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE guild_stats;
+DROP TABLE guild_settings;
+DROP TABLE user_details;
+DROP TABLE user_sources;
+DROP TABLE user_stats;
@@ -0,0 +1,41 @@
+-- Add up migration script here
+CREATE TABLE user_stats (
+	user_id VARCHAR(20) PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+	triggers INTEGER NOT NULL DEFAULT 0,
+	peak_power REAL NOT NULL DEFAULT 0,
+	active_seconds REAL NOT NULL DEFAULT 0,
+	active_since TIMESTAMP NULL
+);
+
+CREATE TABLE user_sources (
+	user_id VARCHAR(20) NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	source VARCHAR(20) NOT NULL,
+	triggers INTEGER NOT NULL DEFAULT 0,
+	power REAL NOT NULL DEFAULT 0,
+	PRIMARY KEY (user_id, source)
+);
+
+CREATE TABLE user_details (
+	user_id VARCHAR(20) NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	kind VARCHAR(16) NOT NULL,
+	detail TEXT NOT NULL,
+	triggers INTEGER NOT NULL DEFAULT 0,
+	power REAL NOT NULL DEFAULT 0,
+	PRIMARY KEY (user_id, kind, detail)
+);
+
+CREATE TABLE guild_settings (
+	guild_id VARCHAR(20) PRIMARY KEY,
+	leaderboard BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE guild_stats (
+	guild_id VARCHAR(20) NOT NULL,
+	week DATE NOT NULL,
+	user_id VARCHAR(20) NOT NULL,
+	received_triggers INTEGER NOT NULL DEFAULT 0,
+	received REAL NOT NULL DEFAULT 0,
+	given_triggers INTEGER NOT NULL DEFAULT 0,
+	given REAL NOT NULL DEFAULT 0,
+	PRIMARY KEY (guild_id, week, user_id)
+);
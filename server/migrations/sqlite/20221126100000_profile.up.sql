@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- SQLite can't drop NOT NULL from avatar without rebuilding users, which would cascade to
+-- everything referencing it, so a missing avatar is stored as an empty string instead.
+ALTER TABLE users ADD COLUMN global_name VARCHAR(32) NULL;
+ALTER TABLE users ADD COLUMN discriminator VARCHAR(4) NULL;
@@ -1,27 +1,158 @@
-use std::sync::Arc;
+use std::{
+	collections::{HashMap, HashSet},
+	num::NonZeroUsize,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use dashmap::DashMap;
+use futures::{
+	future::{BoxFuture, Shared},
+	FutureExt,
+};
+use lru::LruCache;
+use serde::Deserialize;
 use twilight_cache_inmemory::InMemoryCache;
 use twilight_http::Client;
-use twilight_model::id::{
-	marker::{ChannelMarker, MessageMarker, UserMarker},
-	Id,
+use twilight_model::{
+	channel::ReactionType,
+	id::{
+		marker::{ChannelMarker, MessageMarker, UserMarker},
+		Id,
+	},
 };
 
+use crate::config::seconds;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+	/// Maximum amount of messages remembered.
+	pub capacity: usize,
+	/// How long a message is remembered for.
+	#[serde(with = "seconds")]
+	pub ttl: Duration,
+	/// Messages per channel kept by the gateway cache.
+	pub messages_per_channel: usize,
+}
+
+impl Default for CacheSettings {
+	fn default() -> Self {
+		Self {
+			capacity: 10_000,
+			ttl: Duration::from_secs(60 * 60),
+			messages_per_channel: 50,
+		}
+	}
+}
+
+/// What a message has already triggered, so edits and removals can be accounted for.
+#[derive(Default)]
+struct Credits {
+	/// Users this message has already flirted with.
+	flirted: HashSet<Id<UserMarker>>,
+	/// Weight of each reaction on this message, by reacting user and emoji.
+	reactions: HashMap<(Id<UserMarker>, String), f64>,
+}
+
+struct Entry {
+	author: Option<Id<UserMarker>>,
+	credits: Credits,
+	inserted: Instant,
+}
+
+impl Entry {
+	fn new() -> Self {
+		Self {
+			author: None,
+			credits: Default::default(),
+			inserted: Instant::now(),
+		}
+	}
+}
+
+type AuthorFetch = Shared<BoxFuture<'static, Result<Id<UserMarker>, Arc<anyhow::Error>>>>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+	/// Authors found in our own map.
+	pub hits: u64,
+	/// Authors found in the gateway cache.
+	pub gateway_hits: u64,
+	/// Authors that had to be fetched over HTTP.
+	pub misses: u64,
+	/// Lookups that joined an HTTP fetch already in flight.
+	pub batched: u64,
+}
+
 pub struct Cache {
 	cache: Arc<InMemoryCache>,
 	client: Arc<Client>,
-	message_author_map: DashMap<Id<MessageMarker>, Id<UserMarker>>,
+	ttl: Duration,
+	messages: Mutex<LruCache<Id<MessageMarker>, Entry>>,
+	in_flight: DashMap<Id<MessageMarker>, AuthorFetch>,
+	hits: AtomicU64,
+	gateway_hits: AtomicU64,
+	misses: AtomicU64,
+	batched: AtomicU64,
+}
+
+pub fn emoji_key(emoji: &ReactionType) -> String {
+	match emoji {
+		ReactionType::Custom { id, .. } => id.to_string(),
+		ReactionType::Unicode { name } => name.clone(),
+	}
 }
 
 impl Cache {
-	pub fn new(cache: Arc<InMemoryCache>, client: Arc<Client>) -> Self {
+	pub fn new(cache: Arc<InMemoryCache>, client: Arc<Client>, settings: CacheSettings) -> Self {
+		let capacity = NonZeroUsize::new(settings.capacity.max(1)).unwrap();
 		Self {
 			cache,
-			message_author_map: Default::default(),
 			client,
+			ttl: settings.ttl,
+			messages: Mutex::new(LruCache::new(capacity)),
+			in_flight: Default::default(),
+			hits: Default::default(),
+			gateway_hits: Default::default(),
+			misses: Default::default(),
+			batched: Default::default(),
+		}
+	}
+
+	/// Runs `f` on the entry of a message, creating it if it's missing or expired.
+	fn with_entry<T>(&self, message_id: Id<MessageMarker>, f: impl FnOnce(&mut Entry) -> T) -> T {
+		let mut messages = self.messages.lock().unwrap();
+		let expired = messages
+			.peek(&message_id)
+			.map(|entry| entry.inserted.elapsed() > self.ttl)
+			.unwrap_or(false);
+		if expired {
+			messages.pop(&message_id);
 		}
+		f(messages.get_or_insert_mut(message_id, Entry::new))
+	}
+
+	/// Runs `f` on the entry of a message, if there is a live one.
+	fn with_existing<T>(
+		&self,
+		message_id: Id<MessageMarker>,
+		f: impl FnOnce(&mut Entry) -> T,
+	) -> Option<T> {
+		let mut messages = self.messages.lock().unwrap();
+		if messages.peek(&message_id)?.inserted.elapsed() > self.ttl {
+			messages.pop(&message_id);
+			return None;
+		}
+		messages.get_mut(&message_id).map(f)
+	}
+
+	fn set_author(&self, message_id: Id<MessageMarker>, author: Id<UserMarker>) {
+		self.with_entry(message_id, |entry| entry.author = Some(author));
 	}
 
 	pub async fn get_author(
@@ -29,25 +160,130 @@ impl Cache {
 		message_id: Id<MessageMarker>,
 		channel_id: Id<ChannelMarker>,
 	) -> Result<Id<UserMarker>> {
-		if let Some(author) = self.message_author_map.get(&message_id) {
-			return Ok(*author.value());
+		if let Some(author) = self
+			.with_existing(message_id, |entry| entry.author)
+			.flatten()
+		{
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(author);
 		}
 
 		if let Some(entry) = self.cache.message(message_id) {
+			self.gateway_hits.fetch_add(1, Ordering::Relaxed);
 			let author = entry.author();
-			self.message_author_map.insert(message_id, author);
+			self.set_author(message_id, author);
 			return Ok(author);
 		}
 
-		let author = self
-			.client
-			.message(channel_id, message_id)
-			.await?
-			.model()
-			.await?
-			.author
-			.id;
-		self.message_author_map.insert(message_id, author);
+		let mut started = false;
+		let fetch = self
+			.in_flight
+			.entry(message_id)
+			.or_insert_with(|| {
+				started = true;
+				let client = self.client.clone();
+				async move {
+					let message = client
+						.message(channel_id, message_id)
+						.await
+						.map_err(|e| Arc::new(e.into()))?
+						.model()
+						.await
+						.map_err(|e| Arc::new(e.into()))?;
+					Ok(message.author.id)
+				}
+				.boxed()
+				.shared()
+			})
+			.clone();
+		if started {
+			self.misses.fetch_add(1, Ordering::Relaxed);
+		} else {
+			self.batched.fetch_add(1, Ordering::Relaxed);
+		}
+
+		let res = fetch.await;
+		if started {
+			self.in_flight.remove(&message_id);
+		}
+		let author = res.map_err(|e| anyhow!("{:#}", e))?;
+		self.set_author(message_id, author);
 		Ok(author)
 	}
+
+	pub fn stats(&self) -> CacheStats {
+		CacheStats {
+			hits: self.hits.load(Ordering::Relaxed),
+			gateway_hits: self.gateway_hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			batched: self.batched.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Drops expired messages. They would be dropped on access anyway, this just frees the memory.
+	pub fn prune(&self) {
+		let mut messages = self.messages.lock().unwrap();
+		let expired = messages
+			.iter()
+			.filter(|(_, entry)| entry.inserted.elapsed() > self.ttl)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		for id in expired {
+			messages.pop(&id);
+		}
+	}
+
+	/// Credits the message with flirting with `target`,
+	/// answering false if it already had been so the flirt isn't counted twice.
+	pub fn try_credit_flirt(&self, message_id: Id<MessageMarker>, target: Id<UserMarker>) -> bool {
+		self.with_entry(message_id, |entry| entry.credits.flirted.insert(target))
+	}
+
+	/// Takes back a flirt credited with [`Cache::try_credit_flirt`] that didn't go through.
+	pub fn uncredit_flirt(&self, message_id: Id<MessageMarker>, target: Id<UserMarker>) {
+		self.with_existing(message_id, |entry| entry.credits.flirted.remove(&target));
+	}
+
+	pub fn credit_reaction(
+		&self,
+		message_id: Id<MessageMarker>,
+		user_id: Id<UserMarker>,
+		emoji: String,
+		weight: f64,
+	) {
+		self.with_entry(message_id, |entry| {
+			entry.credits.reactions.insert((user_id, emoji), weight)
+		});
+	}
+
+	/// Forgets a reaction, returning the weight it was credited with.
+	pub fn uncredit_reaction(
+		&self,
+		message_id: Id<MessageMarker>,
+		user_id: Id<UserMarker>,
+		emoji: String,
+	) -> Option<f64> {
+		self.with_existing(message_id, |entry| {
+			entry.credits.reactions.remove(&(user_id, emoji))
+		})
+		.flatten()
+	}
+
+	/// Forgets every reaction on a message, returning their combined weight.
+	pub fn uncredit_reactions(&self, message_id: Id<MessageMarker>) -> f64 {
+		self.with_existing(message_id, |entry| {
+			entry
+				.credits
+				.reactions
+				.drain()
+				.map(|(_, weight)| weight)
+				.sum()
+		})
+		.unwrap_or(0.0)
+	}
+
+	/// Drops everything known about a deleted message.
+	pub fn purge(&self, message_id: Id<MessageMarker>) {
+		self.messages.lock().unwrap().pop(&message_id);
+	}
 }
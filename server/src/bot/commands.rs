@@ -0,0 +1,158 @@
+use std::fmt::Write;
+
+use log::{info, warn};
+use twilight_http::Client;
+use twilight_model::{
+	application::{
+		command::{Command, CommandType},
+		interaction::{
+			application_command::{CommandData, CommandOptionValue},
+			Interaction, InteractionData,
+		},
+	},
+	channel::message::{AllowedMentions, MessageFlags},
+	guild::Permissions,
+	http::interaction::{InteractionResponse, InteractionResponseType},
+	id::{marker::ApplicationMarker, Id},
+};
+use twilight_util::builder::{
+	command::{BooleanBuilder, CommandBuilder},
+	InteractionResponseDataBuilder,
+};
+
+use crate::manager::{stats::Ranked, Manager};
+
+const LEADERBOARD: &str = "leaderboard";
+
+fn leaderboard_command() -> Command {
+	CommandBuilder::new(
+		LEADERBOARD,
+		"Show this week's leaderboard",
+		CommandType::ChatInput,
+	)
+	.dm_permission(false)
+	.option(BooleanBuilder::new(
+		"enable",
+		"Turn the leaderboard on or off for this server, needs Manage Server",
+	))
+	.build()
+}
+
+/// Registers the slash commands, replacing whatever was registered before.
+pub async fn register(
+	client: &Client,
+	application_id: Id<ApplicationMarker>,
+) -> anyhow::Result<()> {
+	client
+		.interaction(application_id)
+		.set_global_commands(&[leaderboard_command()])
+		.await?;
+	info!("Registered slash commands");
+	Ok(())
+}
+
+pub async fn handle_interaction(
+	interaction: Interaction,
+	client: &Client,
+	application_id: Id<ApplicationMarker>,
+	manager: &Manager,
+) {
+	let data = match &interaction.data {
+		Some(InteractionData::ApplicationCommand(data)) => data,
+		_ => return,
+	};
+	let (content, ephemeral) = match data.name.as_str() {
+		LEADERBOARD => leaderboard(&interaction, data, manager).await,
+		other => {
+			warn!("Unknown command: {}", other);
+			return;
+		}
+	};
+	let mut response = InteractionResponseDataBuilder::new()
+		.content(content)
+		.allowed_mentions(AllowedMentions::default());
+	if ephemeral {
+		response = response.flags(MessageFlags::EPHEMERAL);
+	}
+	let response = InteractionResponse {
+		kind: InteractionResponseType::ChannelMessageWithSource,
+		data: Some(response.build()),
+	};
+	if let Err(why) = client
+		.interaction(application_id)
+		.create_response(interaction.id, &interaction.token, &response)
+		.await
+	{
+		warn!("Failed to respond to /{}: {}", data.name, why);
+	}
+}
+
+/// Shows the leaderboard, or toggles it when `enable` is passed.
+/// Answers with the reply, and whether only the caller should see it.
+async fn leaderboard(
+	interaction: &Interaction,
+	data: &CommandData,
+	manager: &Manager,
+) -> (String, bool) {
+	let guild_id = match interaction.guild_id {
+		Some(guild_id) => guild_id,
+		None => return ("Leaderboards only exist in servers".into(), true),
+	};
+	let enable = data.options.iter().find_map(|option| match option.value {
+		CommandOptionValue::Boolean(enable) if option.name == "enable" => Some(enable),
+		_ => None,
+	});
+	if let Some(enable) = enable {
+		let allowed = interaction
+			.member
+			.as_ref()
+			.and_then(|member| member.permissions)
+			.map_or(false, |permissions| {
+				permissions.contains(Permissions::MANAGE_GUILD)
+			});
+		if !allowed {
+			return ("You need Manage Server to change that".into(), true);
+		}
+		return match manager.set_leaderboard(guild_id, enable).await {
+			Ok(()) if enable => ("Leaderboard enabled".into(), false),
+			Ok(()) => ("Leaderboard disabled".into(), false),
+			Err(why) => {
+				warn!("Failed to toggle leaderboard of {}: {}", guild_id, why);
+				("Something went wrong, try again later".into(), true)
+			}
+		};
+	}
+	match manager.leaderboard(guild_id).await {
+		Ok(Some(leaderboard)) => {
+			let mut content = format!("**Week of {}**\n", leaderboard.week);
+			write_top(&mut content, "Most praised", &leaderboard.most_praised);
+			write_top(&mut content, "Best flirts", &leaderboard.best_flirts);
+			(content, false)
+		}
+		Ok(None) => (
+			"This server hasn't enabled the leaderboard, someone with Manage Server can run `/leaderboard enable:True`".into(),
+			true,
+		),
+		Err(why) => {
+			warn!("Failed to get leaderboard of {}: {}", guild_id, why);
+			("Something went wrong, try again later".into(), true)
+		}
+	}
+}
+
+fn write_top(content: &mut String, title: &str, top: &[Ranked]) {
+	let _ = writeln!(content, "\n__{}__", title);
+	if top.is_empty() {
+		content.push_str("Nobody yet\n");
+	}
+	for (place, entry) in top.iter().enumerate() {
+		let _ = writeln!(
+			content,
+			"{}. <@{}> {:.1} power from {} triggers",
+			place + 1,
+			entry.id,
+			entry.power,
+			entry.triggers
+		);
+	}
+}
@@ -1,92 +1,297 @@
 //mod flirting;
 //mod voice;
-mod cache;
+pub mod cache;
+mod commands;
+pub mod presence;
 
-use std::{env, sync::Arc};
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use futures::{FutureExt, StreamExt};
 use log::{info, warn};
-use tokio::{select, sync::Notify};
-use twilight_gateway::{Event, EventTypeFlags, Intents, Shard};
+use serde::Deserialize;
+use tokio::{select, sync::Notify, time::Instant};
+use twilight_cache_inmemory::{InMemoryCache, ResourceType};
+use twilight_gateway::{
+	cluster::{Cluster, ShardScheme},
+	Event, EventTypeFlags, Intents,
+};
 use twilight_model::{
-	channel::Message,
+	channel::{message::Mention, Message},
 	gateway::{
-		payload::outgoing::UpdatePresence,
-		presence::{Activity, ActivityType, MinimalActivity, Status},
+		payload::incoming::{MessageDelete, MessageDeleteBulk, MessageUpdate, ReactionRemoveAll},
 		GatewayReaction,
 	},
+	id::{
+		marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker},
+		Id,
+	},
+	util::ImageHash,
 };
 
 use crate::{
-	manager::Manager,
-	user::{Flirt, Reaction},
+	config::Config,
+	manager::{
+		events::{EventKind, NewEvent},
+		throttle::{ThrottleReason, TriggerKind, Verdict},
+		users::UserConnections,
+		Manager,
+	},
+	user::{Flirt, GetPower, IsFlirt, PowerChange, Reaction, Unreact},
 };
 
-use self::cache::Cache;
+use self::{
+	cache::{emoji_key, Cache},
+	presence::build_presence,
+};
 
-pub async fn run_bot(manager: Arc<Manager>, notify_term: Arc<Notify>) -> Result<(), anyhow::Error> {
-	let intents =
-		Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT | Intents::GUILD_MESSAGE_REACTIONS;
-	let event_types = EventTypeFlags::MESSAGE_CREATE | EventTypeFlags::REACTION_ADD;
+/// What to do when someone takes their reaction back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReactionRemoval {
+	/// Take back the power the reaction added.
+	Subtract,
+	/// Keep the power, the reaction already did its job.
+	Ignore,
+}
 
-	let token = env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN var");
+impl Default for ReactionRemoval {
+	fn default() -> Self {
+		ReactionRemoval::Ignore
+	}
+}
 
-	let client = Arc::new(twilight_http::Client::new(token.clone()));
+impl FromStr for ReactionRemoval {
+	type Err = String;
 
-	let im_cache = Arc::new(twilight_cache_inmemory::InMemoryCache::new());
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"subtract" => Ok(ReactionRemoval::Subtract),
+			"ignore" => Ok(ReactionRemoval::Ignore),
+			other => Err(format!("Unknown reaction removal mode: {}", other)),
+		}
+	}
+}
 
-	let cache = Arc::new(Cache::new(im_cache.clone(), client.clone()));
+/// Which shards this process runs.
+/// Without `total` Discord's recommended shard count is used.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShardSettings {
+	pub total: Option<u64>,
+	pub from: Option<u64>,
+	pub to: Option<u64>,
+}
 
-	let (shard, mut events) = Shard::builder(token, intents)
-		.event_types(event_types)
-		.build();
+impl ShardSettings {
+	pub fn validate(&self) -> Result<(), String> {
+		match (self.total, self.from, self.to) {
+			(None, None, None) => Ok(()),
+			(None, _, _) => Err("from and to need total to be set".into()),
+			(Some(0), _, _) => Err("total must be at least 1".into()),
+			(Some(total), from, to) => {
+				let from = from.unwrap_or(0);
+				let to = to.unwrap_or(total - 1);
+				if from > to || to >= total {
+					Err(format!(
+						"{}..={} isn't a range of the {} shards",
+						from, to, total
+					))
+				} else {
+					Ok(())
+				}
+			}
+		}
+	}
+
+	fn scheme(&self) -> ShardScheme {
+		match self.total {
+			Some(total) => ShardScheme::Range {
+				from: self.from.unwrap_or(0),
+				to: self.to.unwrap_or(total.saturating_sub(1)),
+				total,
+			},
+			None => ShardScheme::Auto,
+		}
+	}
+}
+
+pub async fn run_bot(
+	manager: Arc<Manager>,
+	config: Arc<Config>,
+	notify_term: Arc<Notify>,
+) -> Result<(), anyhow::Error> {
+	let mut intents =
+		Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT | Intents::GUILD_MESSAGE_REACTIONS;
+	if config.bot.member_updates {
+		intents |= Intents::GUILD_MEMBERS;
+	}
+	let event_types = EventTypeFlags::READY
+		| EventTypeFlags::RESUMED
+		| EventTypeFlags::SHARD_CONNECTED
+		| EventTypeFlags::SHARD_DISCONNECTED
+		| EventTypeFlags::MESSAGE_CREATE
+		| EventTypeFlags::MESSAGE_UPDATE
+		| EventTypeFlags::MESSAGE_DELETE
+		| EventTypeFlags::MESSAGE_DELETE_BULK
+		| EventTypeFlags::REACTION_ADD
+		| EventTypeFlags::REACTION_REMOVE
+		| EventTypeFlags::REACTION_REMOVE_ALL
+		| EventTypeFlags::INTERACTION_CREATE
+		| EventTypeFlags::MEMBER_UPDATE;
 
-	let shard = Arc::new(shard);
+	let removal = config.bot.reaction_removal;
 
-	shard.start().await.expect("failed to start shard");
+	let token = config.discord.token.clone();
 
-	let minimal_activity = MinimalActivity {
-		kind: ActivityType::Custom,
-		name: "Brrr".into(),
-		url: None,
+	let client = manager.discord.clone();
+
+	let application_id = match config.discord.application_id {
+		Some(id) => id,
+		None => client.current_user_application().await?.model().await?.id,
 	};
+	if let Err(why) = commands::register(&client, application_id).await {
+		warn!("Failed to register slash commands: {}", why);
+	}
 
-	let update_presence = UpdatePresence::new(
-		vec![Activity::from(minimal_activity)],
-		false,
-		None,
-		Status::Online,
-	)
-	.expect("Failed to create UpdatePresence");
+	let cache_settings = config.bot.cache;
+
+	let im_cache = Arc::new(
+		InMemoryCache::builder()
+			.resource_types(ResourceType::MESSAGE | ResourceType::MEMBER)
+			.message_cache_size(cache_settings.messages_per_channel)
+			.build(),
+	);
 
-	let shard_clone = shard.clone();
+	let cache = Arc::new(Cache::new(im_cache.clone(), client.clone(), cache_settings));
 
+	let (cluster, mut events) = Cluster::builder(token, intents)
+		.shard_scheme(config.bot.shards.scheme())
+		.event_types(event_types)
+		.build()
+		.await?;
+
+	let cluster = Arc::new(cluster);
+
+	let cluster_up = cluster.clone();
 	tokio::spawn(async move {
-		tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-		shard_clone
-			.command(&update_presence)
-			.await
-			.expect("Failed to send UpdatePresence");
-		info!("Sent UpdatePresence");
+		cluster_up.up().await;
+		info!("All shards started");
+	});
+
+	let prune_manager = manager.clone();
+	let prune_cache = cache.clone();
+	let prune_cluster = cluster.clone();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(60));
+		loop {
+			interval.tick().await;
+			for (id, info) in prune_cluster.info() {
+				let stage = format!("{:?}", info.stage());
+				let latency = info.latency().average();
+				info!("Shard {}: {} ({:?})", id, stage, latency);
+				prune_manager.shards.update(id, stage, latency);
+			}
+			prune_manager.throttle.prune();
+			prune_cache.prune();
+			let stats = prune_cache.stats();
+			info!(
+				"Message cache: {} hits, {} gateway hits, {} misses, {} batched",
+				stats.hits, stats.gateway_hits, stats.misses, stats.batched
+			);
+		}
 	});
 
+	let presence = config.bot.presence.clone();
+	// Ready already sends the first presence, so the timer only has to keep it fresh after that
+	let mut presence_interval =
+		tokio::time::interval_at(Instant::now() + presence.interval, presence.interval);
+
 	loop {
 		select! {
-			Some(event) = events.next() => {
+			Some((shard_id, event)) = events.next() => {
 				im_cache.update(&event);
+				manager.shards.count_event(shard_id);
 				match event {
+					Event::ShardConnected(_) => {
+						info!("Shard {} connected", shard_id);
+					}
+					Event::ShardDisconnected(disconnected) => {
+						warn!("Shard {} disconnected: {:?}", shard_id, disconnected.reason);
+					}
+					Event::Ready(_) | Event::Resumed => {
+						tokio::spawn(update_presence(
+							cluster.clone(),
+							vec![shard_id],
+							manager.clone(),
+							presence.template.clone(),
+						));
+					}
 					Event::MessageCreate(message) => {
-						tokio::spawn(handle_message(message.0, manager.clone()));
+						tokio::spawn(handle_message(message.0, cache.clone(), manager.clone()));
+					}
+					Event::MessageUpdate(update) => {
+						tokio::spawn(handle_message_update(*update, cache.clone(), manager.clone()));
+					}
+					Event::MessageDelete(MessageDelete { id, .. }) => {
+						cache.purge(id);
+					}
+					Event::MessageDeleteBulk(MessageDeleteBulk { ids, .. }) => {
+						for id in ids {
+							cache.purge(id);
+						}
 					}
 					Event::ReactionAdd(reaction) => {
 						tokio::spawn(handle_reaction(reaction.0, cache.clone(), manager.clone()));
 					}
+					Event::ReactionRemove(reaction) => {
+						tokio::spawn(handle_reaction_remove(
+							reaction.0,
+							removal,
+							cache.clone(),
+							manager.clone(),
+						));
+					}
+					Event::ReactionRemoveAll(removed) => {
+						tokio::spawn(handle_reaction_remove_all(
+							removed,
+							removal,
+							cache.clone(),
+							manager.clone(),
+						));
+					}
+					Event::MemberUpdate(update) => {
+						let user = update.user;
+						tokio::spawn(refresh_profile(
+							manager.clone(),
+							user.id,
+							user.name,
+							user.discriminator,
+							user.avatar,
+						));
+					}
+					Event::InteractionCreate(interaction) => {
+						let client = client.clone();
+						let manager = manager.clone();
+						tokio::spawn(async move {
+							commands::handle_interaction(interaction.0, &client, application_id, &manager)
+								.await;
+						});
+					}
 					_ => {}
 				}
 			}
+			_ = presence_interval.tick() => {
+				let shard_ids = cluster.info().into_keys().collect();
+				tokio::spawn(update_presence(
+					cluster.clone(),
+					shard_ids,
+					manager.clone(),
+					presence.template.clone(),
+				));
+			}
 			_ = notify_term.notified().fuse() => {
-				info!("Shutting down");
-				shard.shutdown();
+				info!("Shutting down all shards");
+				cluster.down();
 				break;
 			}
 		}
@@ -94,17 +299,169 @@ pub async fn run_bot(manager: Arc<Manager>, notify_term: Arc<Notify>) -> Result<
 	Ok(())
 }
 
-async fn handle_message(message: Message, manager: Arc<Manager>) {
-	message
-		.mentions
-		.iter()
-		.filter_map(|mention| {
-			manager.get(mention.id).map(|a| {
-				info!("Brr-ing user: {}", mention.name);
-				a
-			})
-		})
-		.for_each(|user| user.do_send(Flirt(message.content.clone())));
+async fn update_presence(
+	cluster: Arc<Cluster>,
+	shard_ids: Vec<u64>,
+	manager: Arc<Manager>,
+	template: String,
+) {
+	let update = build_presence(&manager, &template).await;
+	for shard_id in shard_ids {
+		if let Err(why) = cluster.command(shard_id, &update).await {
+			warn!(
+				"Failed to send UpdatePresence to shard {}: {}",
+				shard_id, why
+			);
+		}
+	}
+}
+
+async fn refresh_profile(
+	manager: Arc<Manager>,
+	id: Id<UserMarker>,
+	username: String,
+	discriminator: u16,
+	avatar: Option<ImageHash>,
+) {
+	if let Err(why) = manager
+		.refresh_profile(id, &username, discriminator, avatar)
+		.await
+	{
+		warn!("Failed to refresh profile of {}: {}", id, why);
+	}
+}
+
+/// Where a message was sent, and by whom.
+#[derive(Clone, Copy)]
+struct Origin {
+	message_id: Id<MessageMarker>,
+	sender: Id<UserMarker>,
+	guild_id: Option<Id<GuildMarker>>,
+	channel_id: Id<ChannelMarker>,
+}
+
+/// Flirts with every mentioned user the message hasn't flirted with yet.
+async fn flirt(
+	origin: Origin,
+	content: &str,
+	mentions: &[Mention],
+	cache: &Cache,
+	manager: &Manager,
+) {
+	for mention in mentions {
+		// Claimed before anything is awaited, so the message and an edit of it can't both flirt
+		if !cache.try_credit_flirt(origin.message_id, mention.id) {
+			continue;
+		}
+		if !flirt_with(origin, content, mention, manager).await {
+			// Nothing was triggered, a later edit may still flirt with them
+			cache.uncredit_flirt(origin.message_id, mention.id);
+		}
+	}
+}
+
+/// Flirts with one mentioned user, answering whether it went past the throttle.
+async fn flirt_with(origin: Origin, content: &str, mention: &Mention, manager: &Manager) -> bool {
+	let Origin {
+		sender,
+		guild_id,
+		channel_id,
+		..
+	} = origin;
+	let user = match manager.get(mention.id) {
+		Some(user) => user,
+		None => return false,
+	};
+	match user.send(IsFlirt(content.to_owned())).await {
+		Ok(true) => {}
+		Ok(false) => return false,
+		Err(why) => {
+			warn!("Failed to reach user {}: {}", mention.name, why);
+			return false;
+		}
+	}
+	let verdict = manager
+		.throttle
+		.check(sender, mention.id, TriggerKind::Message, Some(content));
+	match verdict {
+		Verdict::Allow(weight) => {
+			info!("Brr-ing user: {}", mention.name);
+			match user.send(Flirt(content.to_owned(), weight)).await {
+				Ok(Some((phrase, change))) => manager.events.record(
+					NewEvent::new(mention.id, EventKind::Message, change)
+						.source(Some(sender), guild_id, channel_id)
+						.detail(phrase),
+				),
+				Ok(None) => {}
+				Err(why) => warn!("Failed to reach user {}: {}", mention.name, why),
+			}
+			true
+		}
+		Verdict::Throttled(reason) => {
+			info!(
+				"Throttled {} flirting with {}: {:?}",
+				sender, mention.name, reason
+			);
+			let source = (sender, guild_id, channel_id);
+			record_throttled(manager, &user, mention.id, source, reason).await;
+			false
+		}
+	}
+}
+
+/// Records a trigger the throttle stopped in the target's history, their power stays as it was.
+async fn record_throttled(
+	manager: &Manager,
+	user: &UserConnections,
+	target: Id<UserMarker>,
+	(sender, guild_id, channel_id): (Id<UserMarker>, Option<Id<GuildMarker>>, Id<ChannelMarker>),
+	reason: ThrottleReason,
+) {
+	let power = match user.send(GetPower).await {
+		Ok(power) => power.unwrap_or(0.0),
+		Err(why) => {
+			warn!("Failed to reach user {}: {}", target, why);
+			0.0
+		}
+	};
+	let change = PowerChange { delta: 0.0, power };
+	manager.events.record(
+		NewEvent::new(target, EventKind::Throttled, change)
+			.source(Some(sender), guild_id, channel_id)
+			.detail(reason.as_str()),
+	);
+}
+
+async fn handle_message(message: Message, cache: Arc<Cache>, manager: Arc<Manager>) {
+	let origin = Origin {
+		message_id: message.id,
+		sender: message.author.id,
+		guild_id: message.guild_id,
+		channel_id: message.channel_id,
+	};
+	flirt(
+		origin,
+		&message.content,
+		&message.mentions,
+		&cache,
+		&manager,
+	)
+	.await;
+}
+
+/// An edit can add praise to a message, which counts once per mentioned user.
+async fn handle_message_update(update: MessageUpdate, cache: Arc<Cache>, manager: Arc<Manager>) {
+	let (author, content, mentions) = match (update.author, update.content, update.mentions) {
+		(Some(author), Some(content), Some(mentions)) => (author, content, mentions),
+		_ => return,
+	};
+	let origin = Origin {
+		message_id: update.id,
+		sender: author.id,
+		guild_id: update.guild_id,
+		channel_id: update.channel_id,
+	};
+	flirt(origin, &content, &mentions, &cache, &manager).await;
 }
 
 async fn handle_reaction(reaction: GatewayReaction, cache: Arc<Cache>, manager: Arc<Manager>) {
@@ -117,7 +474,108 @@ async fn handle_reaction(reaction: GatewayReaction, cache: Arc<Cache>, manager:
 			return;
 		}
 	};
+	let user = match manager.get(author) {
+		Some(user) => user,
+		None => return,
+	};
+	match manager
+		.throttle
+		.check(reaction.user_id, author, TriggerKind::Reaction, None)
+	{
+		Verdict::Allow(weight) => {
+			let emoji = emoji_key(&reaction.emoji);
+			cache.credit_reaction(message_id, reaction.user_id, emoji.clone(), weight);
+			match user.send(Reaction(weight)).await {
+				Ok(change) => manager.events.record(
+					NewEvent::new(author, EventKind::Reaction, change)
+						.source(Some(reaction.user_id), reaction.guild_id, channel_id)
+						.detail(emoji),
+				),
+				Err(why) => warn!("Failed to reach user {}: {}", author, why),
+			}
+		}
+		Verdict::Throttled(reason) => {
+			info!(
+				"Throttled reaction from {} to {}: {:?}",
+				reaction.user_id, author, reason
+			);
+			let source = (reaction.user_id, reaction.guild_id, channel_id);
+			record_throttled(&manager, &user, author, source, reason).await;
+		}
+	}
+}
+
+async fn handle_reaction_remove(
+	reaction: GatewayReaction,
+	removal: ReactionRemoval,
+	cache: Arc<Cache>,
+	manager: Arc<Manager>,
+) {
+	let message_id = reaction.message_id;
+	let emoji = emoji_key(&reaction.emoji);
+	let weight = match cache.uncredit_reaction(message_id, reaction.user_id, emoji.clone()) {
+		Some(weight) => weight,
+		None => return,
+	};
+	if removal == ReactionRemoval::Ignore {
+		return;
+	}
+	let author = match cache.get_author(message_id, reaction.channel_id).await {
+		Ok(author) => author,
+		Err(why) => {
+			warn!("Failed to get author: {}", why);
+			return;
+		}
+	};
 	if let Some(user) = manager.get(author) {
-		user.do_send(Reaction);
+		match user.send(Unreact(weight)).await {
+			// Toys that stopped recorded that themselves
+			Ok(Some(change)) if change.power >= 1e-8 => manager.events.record(
+				NewEvent::new(author, EventKind::Unreact, change)
+					.source(
+						Some(reaction.user_id),
+						reaction.guild_id,
+						reaction.channel_id,
+					)
+					.detail(emoji),
+			),
+			_ => {}
+		}
+	}
+}
+
+async fn handle_reaction_remove_all(
+	removed: ReactionRemoveAll,
+	removal: ReactionRemoval,
+	cache: Arc<Cache>,
+	manager: Arc<Manager>,
+) {
+	let weight = cache.uncredit_reactions(removed.message_id);
+	if removal == ReactionRemoval::Ignore || weight == 0.0 {
+		return;
+	}
+	let author = match cache
+		.get_author(removed.message_id, removed.channel_id)
+		.await
+	{
+		Ok(author) => author,
+		Err(why) => {
+			warn!("Failed to get author: {}", why);
+			return;
+		}
+	};
+	if let Some(user) = manager.get(author) {
+		match user.send(Unreact(weight)).await {
+			Ok(Some(change)) if change.power >= 1e-8 => {
+				manager
+					.events
+					.record(NewEvent::new(author, EventKind::Unreact, change).source(
+						None,
+						removed.guild_id,
+						removed.channel_id,
+					))
+			}
+			_ => {}
+		}
 	}
 }
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use twilight_model::gateway::{
+	payload::outgoing::UpdatePresence,
+	presence::{Activity, ActivityType, MinimalActivity, Status},
+};
+
+use crate::{config::seconds, manager::Manager};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceSettings {
+	/// Status text, `{connected}` and `{active}` are replaced with the live counts.
+	pub template: String,
+	/// How often the status is refreshed.
+	#[serde(with = "seconds")]
+	pub interval: Duration,
+}
+
+impl Default for PresenceSettings {
+	fn default() -> Self {
+		Self {
+			template: "{connected} toys buzzing".into(),
+			interval: Duration::from_secs(60),
+		}
+	}
+}
+
+pub async fn build_presence(manager: &Manager, template: &str) -> UpdatePresence {
+	let stats = manager.connection_stats().await;
+	let name = template
+		.replace("{connected}", &stats.connected.to_string())
+		.replace("{active}", &stats.active.to_string());
+
+	let minimal_activity = MinimalActivity {
+		kind: ActivityType::Custom,
+		name,
+		url: None,
+	};
+
+	UpdatePresence::new(
+		vec![Activity::from(minimal_activity)],
+		false,
+		None,
+		Status::Online,
+	)
+	.expect("Presence always has an activity")
+}
@@ -0,0 +1,500 @@
+use std::{
+	env,
+	fmt::{self, Display},
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+	time::Duration,
+};
+
+use serde::Deserialize;
+use twilight_model::id::{
+	marker::{ApplicationMarker, UserMarker},
+	Id,
+};
+
+use crate::{
+	bot::{cache::CacheSettings, presence::PresenceSettings, ReactionRemoval, ShardSettings},
+	manager::{database, throttle::ThrottleSettings},
+	server::{session::decode_key, store::StoreKind, HttpSettings},
+};
+
+/// Where the config is read from when `EUPHORIA_CONFIG` isn't set.
+const DEFAULT_PATH: &str = "euphoria.toml";
+
+/// Durations are written as a number of seconds.
+pub mod seconds {
+	use std::time::Duration;
+
+	use serde::{de::Error, Deserialize, Deserializer};
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+		let secs = f64::deserialize(deserializer)?;
+		if !secs.is_finite() || secs < 0.0 {
+			return Err(D::Error::custom(
+				"expected a non-negative number of seconds",
+			));
+		}
+		Ok(Duration::from_secs_f64(secs))
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Refuses insecure fallbacks, like a generated session key.
+	pub production: bool,
+	pub discord: DiscordConfig,
+	pub oauth: OAuthConfig,
+	pub database: DatabaseConfig,
+	pub session: SessionConfig,
+	pub http: HttpSettings,
+	pub throttle: ThrottleSettings,
+	pub bot: BotConfig,
+	pub admin: AdminConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+	pub token: String,
+	pub application_id: Option<Id<ApplicationMarker>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+	pub client_id: String,
+	pub client_secret: String,
+	pub redirect_uri: String,
+	/// Protect the login flow with PKCE on top of the state parameter.
+	pub pkce: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+	pub url: String,
+	/// Apply pending migrations on startup. When off, startup fails while any are pending.
+	pub migrate: bool,
+	/// Base64 encoded 32 byte key OAuth tokens are encrypted with.
+	pub token_key: Option<String>,
+	/// Keys tokens were encrypted with before, still accepted until re-encrypted.
+	pub previous_token_keys: Vec<String>,
+}
+
+impl Default for DatabaseConfig {
+	fn default() -> Self {
+		Self {
+			url: String::new(),
+			migrate: true,
+			token_key: None,
+			previous_token_keys: Vec::new(),
+		}
+	}
+}
+
+impl DatabaseConfig {
+	fn apply_env(&mut self, env: &mut Overrides, errors: &mut Vec<String>) {
+		env.set("DATABASE_URL", &mut self.url);
+		if let Ok(value) = env::var("DATABASE_MIGRATE") {
+			if let Some(migrate) = parse_bool("DATABASE_MIGRATE", &value, errors) {
+				self.migrate = migrate;
+			}
+		}
+		env.set_opt("TOKEN_KEY", &mut self.token_key);
+		env.set_list("TOKEN_PREVIOUS_KEYS", &mut self.previous_token_keys);
+	}
+
+	fn validate(&self, production: bool) -> Vec<String> {
+		let mut errors = Vec::new();
+		if self.url.is_empty() {
+			errors.push("database.url (DATABASE_URL) is not set".into());
+		} else if !database::supported_url(&self.url) {
+			errors
+				.push("database.url (DATABASE_URL) must start with postgres:// or sqlite:".into());
+		}
+		match &self.token_key {
+			Some(key) => {
+				if let Err(e) = database::decode_token_key(key) {
+					errors.push(format!("database.token_key (TOKEN_KEY): {}", e));
+				}
+			}
+			None if production => {
+				errors.push("database.token_key (TOKEN_KEY) must be set in production".into());
+			}
+			None => {}
+		}
+		for (i, key) in self.previous_token_keys.iter().enumerate() {
+			if let Err(e) = database::decode_token_key(key) {
+				errors.push(format!("database.previous_token_keys[{}]: {}", i, e));
+			}
+		}
+		errors
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+	/// Defaults to Redis when `redis_uri` is set, memory otherwise.
+	pub store: Option<StoreKind>,
+	pub redis_uri: Option<String>,
+	/// Base64 encoded key of at least 64 bytes.
+	pub key: Option<String>,
+	/// File containing the key, used when `key` isn't set.
+	pub key_file: Option<PathBuf>,
+	/// Keys used before the current one, still accepted for existing sessions.
+	pub previous_keys: Vec<String>,
+}
+
+impl SessionConfig {
+	pub fn store(&self) -> StoreKind {
+		match (self.store, &self.redis_uri) {
+			(Some(store), _) => store,
+			(None, Some(_)) => StoreKind::Redis,
+			(None, None) => StoreKind::Memory,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+	pub reaction_removal: ReactionRemoval,
+	/// Keep stored profiles fresh from member updates, needs the privileged Server Members intent.
+	pub member_updates: bool,
+	pub presence: PresenceSettings,
+	pub cache: CacheSettings,
+	pub shards: ShardSettings,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+	/// Discord user IDs of the operators allowed to use the admin API.
+	pub ids: Vec<String>,
+}
+
+impl AdminConfig {
+	/// The operators' IDs, leaving out any that don't parse, `validate` complains about those.
+	pub fn ids(&self) -> Vec<Id<UserMarker>> {
+		self.ids.iter().filter_map(|id| id.parse().ok()).collect()
+	}
+}
+
+/// Every problem found with the config, so they can all be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "Invalid configuration:")?;
+		for error in &self.0 {
+			writeln!(f, "  - {}", error)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Applies environment variables on top of the config file, remembering the ones that don't parse.
+#[derive(Default)]
+struct Overrides {
+	errors: Vec<String>,
+}
+
+impl Overrides {
+	fn set<T: FromStr>(&mut self, name: &str, target: &mut T)
+	where
+		T::Err: Display,
+	{
+		if let Ok(value) = env::var(name) {
+			match value.parse() {
+				Ok(value) => *target = value,
+				Err(e) => self.errors.push(format!("{}: {}", name, e)),
+			}
+		}
+	}
+
+	fn set_opt<T: FromStr>(&mut self, name: &str, target: &mut Option<T>)
+	where
+		T::Err: Display,
+	{
+		if let Ok(value) = env::var(name) {
+			match value.parse() {
+				Ok(value) => *target = Some(value),
+				Err(e) => self.errors.push(format!("{}: {}", name, e)),
+			}
+		}
+	}
+
+	fn set_secs(&mut self, name: &str, target: &mut Duration) {
+		let mut secs = None;
+		self.set_opt::<f64>(name, &mut secs);
+		match secs {
+			Some(secs) if secs.is_finite() && secs >= 0.0 => {
+				*target = Duration::from_secs_f64(secs)
+			}
+			Some(_) => self.errors.push(format!(
+				"{}: expected a non-negative number of seconds",
+				name
+			)),
+			None => {}
+		}
+	}
+
+	fn set_list(&mut self, name: &str, target: &mut Vec<String>) {
+		if let Ok(value) = env::var(name) {
+			*target = value
+				.split(',')
+				.map(|item| item.trim().to_owned())
+				.filter(|item| !item.is_empty())
+				.collect();
+		}
+	}
+}
+
+fn parse_bool(name: &str, value: &str, errors: &mut Vec<String>) -> Option<bool> {
+	match value {
+		"true" | "1" => Some(true),
+		"false" | "0" => Some(false),
+		other => {
+			errors.push(format!("{}: expected true or false, got {}", name, other));
+			None
+		}
+	}
+}
+
+/// Reads the config file at `EUPHORIA_CONFIG`, or `euphoria.toml` if it exists.
+fn read_file() -> Result<Option<(PathBuf, String)>, ConfigError> {
+	let (path, required) = match env::var("EUPHORIA_CONFIG") {
+		Ok(path) => (PathBuf::from(path), true),
+		Err(_) => (PathBuf::from(DEFAULT_PATH), false),
+	};
+	match fs::read_to_string(&path) {
+		Ok(content) => Ok(Some((path, content))),
+		Err(e) if required => Err(ConfigError(vec![format!(
+			"Couldn't read {}: {}",
+			path.display(),
+			e
+		)])),
+		Err(_) => Ok(None),
+	}
+}
+
+/// The parts of the config file [`Config::load_database`] looks at, other sections are skipped.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct DatabaseFile {
+	production: bool,
+	database: DatabaseConfig,
+}
+
+impl Config {
+	/// Reads the config file at `EUPHORIA_CONFIG` (or `euphoria.toml` if it exists),
+	/// applies environment overrides and validates the result.
+	pub fn load() -> Result<Self, ConfigError> {
+		let mut config = match read_file()? {
+			Some((path, content)) => toml::from_str(&content)
+				.map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?,
+			None => Config::default(),
+		};
+		let mut errors = config.apply_env();
+		errors.extend(config.validate());
+		if errors.is_empty() {
+			Ok(config)
+		} else {
+			Err(ConfigError(errors))
+		}
+	}
+
+	/// Like [`Config::load`], but only reads and validates the `database` section,
+	/// so migrations can run without the rest of the config.
+	pub fn load_database() -> Result<DatabaseConfig, ConfigError> {
+		let file: DatabaseFile = match read_file()? {
+			Some((path, content)) => toml::from_str(&content)
+				.map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?,
+			None => DatabaseFile::default(),
+		};
+		let production = match env::var("EUPHORIA_ENV") {
+			Ok(value) => value == "production",
+			Err(_) => file.production,
+		};
+		let mut database = file.database;
+		let mut env = Overrides::default();
+		let mut errors = Vec::new();
+		database.apply_env(&mut env, &mut errors);
+		errors.extend(env.errors);
+		errors.extend(database.validate(production));
+		if errors.is_empty() {
+			Ok(database)
+		} else {
+			Err(ConfigError(errors))
+		}
+	}
+
+	/// Environment variables take precedence over the file.
+	fn apply_env(&mut self) -> Vec<String> {
+		let mut env = Overrides::default();
+		let mut errors = Vec::new();
+
+		if let Ok(value) = env::var("EUPHORIA_ENV") {
+			self.production = value == "production";
+		}
+
+		env.set("DISCORD_TOKEN", &mut self.discord.token);
+		env.set_opt("APPLICATION_ID", &mut self.discord.application_id);
+
+		env.set("CLIENT_ID", &mut self.oauth.client_id);
+		env.set("CLIENT_SECRET", &mut self.oauth.client_secret);
+		env.set("REDIRECT_URI", &mut self.oauth.redirect_uri);
+		if let Ok(value) = env::var("OAUTH_PKCE") {
+			if let Some(pkce) = parse_bool("OAUTH_PKCE", &value, &mut errors) {
+				self.oauth.pkce = pkce;
+			}
+		}
+
+		self.database.apply_env(&mut env, &mut errors);
+
+		env.set_opt("SESSION_STORE", &mut self.session.store);
+		env.set_opt("REDIS_URI", &mut self.session.redis_uri);
+		env.set_opt("SESSION_KEY", &mut self.session.key);
+		env.set_opt("SESSION_KEY_FILE", &mut self.session.key_file);
+		env.set_list("SESSION_PREVIOUS_KEYS", &mut self.session.previous_keys);
+
+		env.set("HTTP_ADDRESS", &mut self.http.address);
+		env.set("HTTP_PORT", &mut self.http.port);
+		env.set_opt("TLS_CERT", &mut self.http.tls_cert);
+		env.set_opt("TLS_KEY", &mut self.http.tls_key);
+		env.set_list("CORS_ORIGINS", &mut self.http.cors_origins);
+
+		let throttle = &mut self.throttle;
+		env.set_secs("THROTTLE_SENDER_COOLDOWN", &mut throttle.sender_cooldown);
+		env.set("THROTTLE_TARGET_CAP", &mut throttle.target_cap);
+		env.set_secs("THROTTLE_TARGET_WINDOW", &mut throttle.target_window);
+		env.set("THROTTLE_REPEAT_FALLOFF", &mut throttle.repeat_falloff);
+
+		let bot = &mut self.bot;
+		env.set("REACTION_REMOVAL", &mut bot.reaction_removal);
+		if let Ok(value) = env::var("MEMBER_UPDATES") {
+			if let Some(member_updates) = parse_bool("MEMBER_UPDATES", &value, &mut errors) {
+				bot.member_updates = member_updates;
+			}
+		}
+		env.set("PRESENCE_TEMPLATE", &mut bot.presence.template);
+		env.set_secs("PRESENCE_INTERVAL", &mut bot.presence.interval);
+		env.set("MESSAGE_CACHE_CAPACITY", &mut bot.cache.capacity);
+		env.set_secs("MESSAGE_CACHE_TTL", &mut bot.cache.ttl);
+		env.set("MESSAGES_PER_CHANNEL", &mut bot.cache.messages_per_channel);
+		env.set_opt("SHARD_TOTAL", &mut bot.shards.total);
+		env.set_opt("SHARD_FROM", &mut bot.shards.from);
+		env.set_opt("SHARD_TO", &mut bot.shards.to);
+
+		env.set_list("ADMIN_IDS", &mut self.admin.ids);
+
+		errors.extend(env.errors);
+		errors
+	}
+
+	fn validate(&self) -> Vec<String> {
+		let mut errors = Vec::new();
+		let mut required = |value: &str, name: &str| {
+			if value.is_empty() {
+				errors.push(format!("{} is not set", name));
+			}
+		};
+		required(&self.discord.token, "discord.token (DISCORD_TOKEN)");
+		required(&self.oauth.client_id, "oauth.client_id (CLIENT_ID)");
+		required(
+			&self.oauth.client_secret,
+			"oauth.client_secret (CLIENT_SECRET)",
+		);
+		required(
+			&self.oauth.redirect_uri,
+			"oauth.redirect_uri (REDIRECT_URI)",
+		);
+		errors.extend(self.database.validate(self.production));
+
+		let session = &self.session;
+		if session.store() == StoreKind::Redis && session.redis_uri.is_none() {
+			errors.push(
+				"session.redis_uri (REDIS_URI) is required by the redis session store".into(),
+			);
+		}
+		match (&session.key, &session.key_file) {
+			(Some(key), _) => {
+				if let Err(e) = decode_key(key) {
+					errors.push(format!("session.key (SESSION_KEY): {}", e));
+				}
+			}
+			(None, Some(path)) => match fs::read_to_string(path) {
+				Ok(key) => {
+					if let Err(e) = decode_key(&key) {
+						errors.push(format!("session.key_file {}: {}", path.display(), e));
+					}
+				}
+				Err(e) => errors.push(format!(
+					"Couldn't read session.key_file {}: {}",
+					path.display(),
+					e
+				)),
+			},
+			(None, None) if self.production => {
+				errors.push("session.key or session.key_file (SESSION_KEY or SESSION_KEY_FILE) must be set in production".into());
+			}
+			(None, None) => {}
+		}
+		for (i, key) in session.previous_keys.iter().enumerate() {
+			if let Err(e) = decode_key(key) {
+				errors.push(format!("session.previous_keys[{}]: {}", i, e));
+			}
+		}
+
+		let http = &self.http;
+		match (&http.tls_cert, &http.tls_key) {
+			(Some(cert), Some(key)) => {
+				for path in [cert, key] {
+					if !Path::new(path).is_file() {
+						errors.push(format!("TLS file {} doesn't exist", path.display()));
+					}
+				}
+			}
+			(None, None) => {}
+			_ => errors.push(
+				"http.tls_cert and http.tls_key (TLS_CERT and TLS_KEY) must be set together".into(),
+			),
+		}
+
+		let throttle = &self.throttle;
+		if !(throttle.repeat_falloff > 0.0 && throttle.repeat_falloff <= 1.0) {
+			errors.push("throttle.repeat_falloff must be above 0 and at most 1".into());
+		}
+		if throttle.target_cap == 0 {
+			errors.push("throttle.target_cap must be at least 1".into());
+		}
+
+		let bot = &self.bot;
+		if bot.cache.capacity == 0 {
+			errors.push("bot.cache.capacity must be at least 1".into());
+		}
+		if bot.presence.interval.is_zero() {
+			errors.push("bot.presence.interval must be above 0".into());
+		}
+		if let Err(e) = bot.shards.validate() {
+			errors.push(format!("bot.shards: {}", e));
+		}
+
+		for id in &self.admin.ids {
+			if id.parse::<Id<UserMarker>>().is_err() {
+				errors.push(format!(
+					"admin.ids (ADMIN_IDS): {} isn't a Discord user ID",
+					id
+				));
+			}
+		}
+
+		errors
+	}
+}
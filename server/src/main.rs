@@ -1,13 +1,15 @@
 mod bot;
+mod config;
 mod manager;
 mod regex;
 mod server;
 mod user;
 
-use std::sync::Arc;
+use std::{env, sync::Arc};
 
 use bot::run_bot;
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
+use config::Config;
 use dotenv::dotenv;
 use futures::TryFutureExt;
 use log::{error, info};
@@ -27,6 +29,23 @@ fn main() -> Result<()> {
 	color_eyre::install()?;
 	dotenv().ok();
 
+	let migrate_only = env::args().skip(1).any(|arg| arg == "--migrate-only");
+	let reencrypt_tokens = env::args().skip(1).any(|arg| arg == "--reencrypt-tokens");
+
+	if migrate_only {
+		// Deploy steps only have the database settings at hand, the rest isn't needed
+		let database = Config::load_database()?;
+		let rt = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.expect("Couldn't start runtime");
+		return rt
+			.block_on(manager::migrate_only(&database))
+			.map_err(|e| eyre!(e));
+	}
+
+	let config = Arc::new(Config::load()?);
+
 	let notify_term = Arc::new(Notify::new());
 
 	let rt = tokio::runtime::Builder::new_multi_thread()
@@ -46,21 +65,36 @@ fn main() -> Result<()> {
 	});
 
 	rt.block_on(async move {
-		let manager = Arc::new(manager::Manager::new().await);
+		let manager = Arc::new(manager::Manager::new(&config).await);
+
+		manager.prepare_database(config.database.migrate).await?;
+		if reencrypt_tokens {
+			return manager.reencrypt_tokens().await;
+		}
+
+		tokio::spawn(manager::run_token_refresh(
+			manager.clone(),
+			notify_term.clone(),
+		));
+		tokio::spawn(manager::run_event_writer(
+			manager.clone(),
+			notify_term.clone(),
+		));
 
 		let server_set = LocalSet::new();
 		let server = server_set
-			.spawn_local(run_http_server(manager.clone()))
+			.spawn_local(run_http_server(manager.clone(), config.clone()))
 			.map_err(anyhow::Error::from);
 
 		let discord_set = LocalSet::new();
 		let bot = discord_set
-			.spawn_local(run_bot(manager, notify_term))
+			.spawn_local(run_bot(manager, config, notify_term))
 			.map_err(anyhow::Error::from);
 
 		info!("Starting backend");
 
 		futures::join!(server_set, discord_set);
-	});
-	Ok(())
+		Ok(())
+	})
+	.map_err(|e: anyhow::Error| eyre!(e))
 }
@@ -0,0 +1,306 @@
+use chrono::{NaiveDateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use twilight_model::id::{marker::UserMarker, Id};
+
+use super::{
+	error,
+	events::{Event, EventKind},
+	stats::{GuildStats, Ranked, Totals},
+	Manager, User,
+};
+
+/// Everything stored about a user. Tokens are left out, they're Discord's secrets, not the user's data.
+#[derive(Debug, Serialize)]
+pub struct Export {
+	pub exported_at: NaiveDateTime,
+	pub profile: User,
+	pub totals: Totals,
+	/// Who triggered the user, and how much.
+	pub sources: Vec<Ranked>,
+	/// Phrases that triggered the user, and how much.
+	pub phrases: Vec<Ranked>,
+	/// Emojis that triggered the user, and how much.
+	pub emojis: Vec<Ranked>,
+	pub guild_stats: Vec<GuildStats>,
+	/// Oldest first.
+	pub events: Vec<Event>,
+}
+
+/// account impls
+impl Manager {
+	/// `None` if the user doesn't exist.
+	pub async fn export(&self, id: Id<UserMarker>) -> error::Result<Option<Export>> {
+		let id = id.to_string();
+		let profile = match self.db.storage().get_user(&id).await? {
+			Some(profile) => profile,
+			None => return Ok(None),
+		};
+		Ok(Some(Export {
+			exported_at: Utc::now().naive_utc(),
+			profile,
+			totals: self.db.storage().totals(&id).await?.unwrap_or_default(),
+			sources: self.db.storage().top_sources(&id, i64::MAX).await?,
+			phrases: self
+				.db
+				.storage()
+				.top_details(&id, EventKind::Message.as_str(), i64::MAX)
+				.await?,
+			emojis: self
+				.db
+				.storage()
+				.top_details(&id, EventKind::Reaction.as_str(), i64::MAX)
+				.await?,
+			guild_stats: self.db.storage().guild_stats(&id).await?,
+			events: self.db.storage().all_events(&id).await?,
+		}))
+	}
+
+	/// Disconnects the user's toys, revokes their Discord token and deletes everything stored about them.
+	/// Their sessions stop being accepted along with the user, also if they log in again,
+	/// and are purged the next time they're used.
+	pub async fn delete_account(&self, id: Id<UserMarker>) -> error::Result<()> {
+		self.disconnect_all(id);
+		let id = id.to_string();
+		// The account goes either way, the token is forgotten with it
+		match self.db.get_token(&id).await {
+			Ok(Some(token)) => {
+				if let Err(e) = self
+					.auth
+					.revoke_tokens(&token.access_token, &token.refresh_token)
+					.await
+				{
+					warn!("Failed to revoke token of deleted user {}: {}", id, e);
+				}
+			}
+			Ok(None) => {}
+			Err(e) => warn!("Can't revoke token of deleted user {}: {}", id, e),
+		}
+		self.db.storage().delete_user(&id).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, fs, path::PathBuf, process};
+
+	use sqlx::SqlitePool;
+	use twilight_model::id::{
+		marker::{ChannelMarker, GuildMarker},
+		Id,
+	};
+
+	use crate::{
+		config::{Config, DatabaseConfig},
+		manager::{auth::AccessToken, events::NewEvent, stats},
+		user::PowerChange,
+	};
+
+	use super::*;
+
+	const GUILD: Id<GuildMarker> = Id::new(10);
+	const CHANNEL: Id<ChannelMarker> = Id::new(20);
+
+	/// A SQLite database file that's removed again once the test is done with it.
+	struct TempDb(PathBuf);
+
+	impl TempDb {
+		fn new(name: &str) -> Self {
+			let path = env::temp_dir().join(format!("euphoria-{}-{}.sqlite", name, process::id()));
+			let db = Self(path);
+			db.remove();
+			db
+		}
+
+		fn url(&self) -> String {
+			format!("sqlite:{}", self.0.display())
+		}
+
+		fn remove(&self) {
+			for suffix in ["", "-wal", "-shm"] {
+				let mut path = self.0.clone().into_os_string();
+				path.push(suffix);
+				let _ = fs::remove_file(path);
+			}
+		}
+	}
+
+	impl Drop for TempDb {
+		fn drop(&mut self) {
+			self.remove();
+		}
+	}
+
+	/// A manager on a fresh SQLite database, and the database.
+	async fn manager(name: &str) -> (Manager, TempDb) {
+		let db = TempDb::new(name);
+		let config = Config {
+			database: DatabaseConfig {
+				url: db.url(),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		let manager = Manager::new(&config).await;
+		manager.migrate().await.unwrap();
+		(manager, db)
+	}
+
+	/// Stores a user without tokens, so deleting them doesn't try to revoke anything.
+	async fn add_user(manager: &Manager, id: Id<UserMarker>, username: &str) {
+		let user = User {
+			id: id.to_string(),
+			username: username.to_owned(),
+			global_name: None,
+			discriminator: None,
+			avatar: None,
+		};
+		manager.db.save_user(&user, &token()).await.unwrap();
+		manager.db.storage().clear_tokens(&user.id).await.unwrap();
+	}
+
+	fn token() -> AccessToken {
+		AccessToken {
+			access_token: "access".into(),
+			token_type: "Bearer".into(),
+			expires_in: 3600,
+			refresh_token: "refresh".into(),
+		}
+	}
+
+	/// Writes the event like the event writer does.
+	async fn record(manager: &Manager, event: NewEvent) {
+		manager.db.storage().insert_event(&event).await.unwrap();
+		stats::aggregate(manager.db.storage(), &event)
+			.await
+			.unwrap();
+	}
+
+	fn flirt(target: Id<UserMarker>, source: Id<UserMarker>) -> NewEvent {
+		let change = PowerChange {
+			delta: 0.3,
+			power: 0.3,
+		};
+		NewEvent::new(target, EventKind::Message, change)
+			.source(Some(source), Some(GUILD), CHANNEL)
+			.detail("good girl")
+	}
+
+	fn reaction(target: Id<UserMarker>, source: Id<UserMarker>) -> NewEvent {
+		let change = PowerChange {
+			delta: 0.3,
+			power: 0.3,
+		};
+		NewEvent::new(target, EventKind::Reaction, change)
+			.source(Some(source), Some(GUILD), CHANNEL)
+			.detail("❤️")
+	}
+
+	fn stop(target: Id<UserMarker>) -> NewEvent {
+		let change = PowerChange {
+			delta: -0.3,
+			power: 0.0,
+		};
+		NewEvent::new(target, EventKind::Stop, change).detail("decay")
+	}
+
+	async fn count(pool: &SqlitePool, query: &str, id: Id<UserMarker>) -> i64 {
+		sqlx::query_scalar(query)
+			.bind(id.to_string())
+			.fetch_one(pool)
+			.await
+			.unwrap()
+	}
+
+	#[actix_web::test]
+	async fn export_contains_everything_about_the_user() {
+		let (manager, _) = manager("export").await;
+		let (kitten, flirter) = (Id::new(1), Id::new(2));
+		add_user(&manager, kitten, "kitten").await;
+		add_user(&manager, flirter, "flirter").await;
+		record(&manager, flirt(kitten, flirter)).await;
+		record(&manager, stop(kitten)).await;
+		record(&manager, reaction(flirter, kitten)).await;
+
+		let export = manager.export(kitten).await.unwrap().unwrap();
+		assert_eq!(export.profile.username, "kitten");
+		assert_eq!(export.totals.triggers, 1);
+		assert_eq!(export.sources.len(), 1);
+		assert_eq!(export.sources[0].id, flirter.to_string());
+		assert_eq!(export.phrases.len(), 1);
+		assert_eq!(export.phrases[0].id, "good girl");
+		assert!(export.emojis.is_empty());
+		assert_eq!(export.guild_stats.len(), 1);
+		assert_eq!(export.guild_stats[0].guild_id, GUILD.to_string());
+		assert_eq!(export.guild_stats[0].received_triggers, 1);
+		assert_eq!(export.guild_stats[0].given_triggers, 1);
+		let kinds = export
+			.events
+			.iter()
+			.map(|event| event.kind.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(kinds, ["message", "stop"]);
+
+		assert!(manager.export(Id::new(3)).await.unwrap().is_none());
+	}
+
+	#[actix_web::test]
+	async fn delete_removes_everything_about_the_user() {
+		let (manager, db) = manager("delete").await;
+		let (kitten, flirter) = (Id::new(1), Id::new(2));
+		add_user(&manager, kitten, "kitten").await;
+		add_user(&manager, flirter, "flirter").await;
+		record(&manager, flirt(kitten, flirter)).await;
+		record(&manager, stop(kitten)).await;
+		record(&manager, reaction(flirter, kitten)).await;
+		record(&manager, stop(flirter)).await;
+		manager
+			.db
+			.storage()
+			.bump_session_epoch(&kitten.to_string())
+			.await
+			.unwrap();
+		let epoch = manager.session_epoch(&kitten.to_string()).await.unwrap();
+
+		manager.delete_account(kitten).await.unwrap();
+
+		let pool = SqlitePool::connect(&db.url()).await.unwrap();
+		for query in [
+			"SELECT COUNT(*) FROM users WHERE id = ?",
+			"SELECT COUNT(*) FROM user_stats WHERE user_id = ?",
+			"SELECT COUNT(*) FROM user_sources WHERE user_id = ? OR source = ?1",
+			"SELECT COUNT(*) FROM user_details WHERE user_id = ?",
+			"SELECT COUNT(*) FROM guild_stats WHERE user_id = ?",
+			"SELECT COUNT(*) FROM events WHERE target = ? OR source = ?1",
+		] {
+			assert_eq!(count(&pool, query, kitten).await, 0, "{}", query);
+		}
+		// What the user did to others stays, without saying who did it
+		let events = sqlx::query_as::<_, (String, Option<String>)>(
+			"SELECT kind, source FROM events WHERE target = ? ORDER BY id",
+		)
+		.bind(flirter.to_string())
+		.fetch_all(&pool)
+		.await
+		.unwrap();
+		assert_eq!(
+			events,
+			[("reaction".to_owned(), None), ("stop".to_owned(), None)]
+		);
+		assert_eq!(
+			count(
+				&pool,
+				"SELECT COUNT(*) FROM user_stats WHERE user_id = ?",
+				flirter
+			)
+			.await,
+			1
+		);
+
+		// Coming back doesn't bring old sessions back to life
+		add_user(&manager, kitten, "kitten").await;
+		let new_epoch = manager.session_epoch(&kitten.to_string()).await.unwrap();
+		assert!(new_epoch > epoch, "{:?} > {:?}", new_epoch, epoch);
+	}
+}
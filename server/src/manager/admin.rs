@@ -0,0 +1,201 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{marker::UserMarker, Id};
+
+use crate::user::ForceStop;
+
+use super::{database, error, stats::GuildSettings, users::ConnectionStatus, Manager};
+
+/// Default and maximum amount of entries per audit log page.
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+	ListConnections,
+	StopUser,
+	DisconnectUser,
+	ListGuilds,
+	ListShards,
+	ListBans,
+	Ban,
+	Unban,
+	ViewAudit,
+}
+
+impl AuditAction {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			AuditAction::ListConnections => "list_connections",
+			AuditAction::StopUser => "stop_user",
+			AuditAction::DisconnectUser => "disconnect_user",
+			AuditAction::ListGuilds => "list_guilds",
+			AuditAction::ListShards => "list_shards",
+			AuditAction::ListBans => "list_bans",
+			AuditAction::Ban => "ban",
+			AuditAction::Unban => "unban",
+			AuditAction::ViewAudit => "view_audit",
+		}
+	}
+}
+
+/// How an admin action went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOutcome {
+	Done,
+	/// The user or thing it was taken against doesn't exist.
+	NotFound,
+	Failed,
+}
+
+impl AuditOutcome {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			AuditOutcome::Done => "done",
+			AuditOutcome::NotFound => "not_found",
+			AuditOutcome::Failed => "failed",
+		}
+	}
+}
+
+/// An admin action, waiting to be written.
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+	pub admin_id: String,
+	pub action: AuditAction,
+	/// The user the action was taken against.
+	pub target: Option<String>,
+	pub detail: Option<String>,
+	pub outcome: AuditOutcome,
+	pub created_at: NaiveDateTime,
+}
+
+/// A stored admin action.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+	pub id: i64,
+	pub admin_id: String,
+	pub action: String,
+	pub target: Option<String>,
+	pub detail: Option<String>,
+	pub outcome: String,
+	pub created_at: NaiveDateTime,
+}
+
+/// Which part of the audit log to return, newest first.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+	/// Only entries older than this entry id, for the next page.
+	pub before: Option<i64>,
+	pub limit: Option<i64>,
+}
+
+impl AuditFilter {
+	pub fn limit(&self) -> i64 {
+		self.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE)
+	}
+}
+
+/// A user who isn't allowed to use the service.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Ban {
+	pub user_id: String,
+	pub reason: Option<String>,
+	pub banned_by: String,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectedUser {
+	pub user_id: Id<UserMarker>,
+	pub connections: Vec<ConnectionStatus>,
+}
+
+/// admin impls
+impl Manager {
+	pub fn is_admin(&self, id: Id<UserMarker>) -> bool {
+		self.admins.contains(&id)
+	}
+
+	/// Writes an admin action to the audit log, along with how it went.
+	pub async fn audit(
+		&self,
+		admin: Id<UserMarker>,
+		action: AuditAction,
+		target: Option<Id<UserMarker>>,
+		detail: Option<String>,
+		outcome: AuditOutcome,
+	) -> database::Result<()> {
+		let entry = NewAuditEntry {
+			admin_id: admin.to_string(),
+			action,
+			target: target.map(|id| id.to_string()),
+			detail,
+			outcome,
+			created_at: Utc::now().naive_utc(),
+		};
+		self.db.storage().insert_audit(&entry).await
+	}
+
+	pub async fn audit_log(&self, filter: &AuditFilter) -> database::Result<Vec<AuditEntry>> {
+		self.db.storage().audit_log(filter).await
+	}
+
+	/// Every connected user, with what their connections are up to.
+	pub async fn connected_users(&self) -> Vec<ConnectedUser> {
+		let mut users = Vec::new();
+		for user_id in self.user_manager.users() {
+			users.push(ConnectedUser {
+				user_id,
+				connections: self.user_manager.status(user_id).await,
+			});
+		}
+		users
+	}
+
+	/// Stops the user's toys without disconnecting them, returning whether they were connected.
+	pub fn force_stop(&self, id: Id<UserMarker>) -> bool {
+		match self.get(id) {
+			Some(connections) => {
+				connections.do_send(ForceStop);
+				true
+			}
+			None => false,
+		}
+	}
+
+	pub async fn guild_settings(&self) -> database::Result<Vec<GuildSettings>> {
+		self.db.storage().guild_settings().await
+	}
+
+	pub async fn is_banned(&self, id: &str) -> database::Result<bool> {
+		Ok(self.db.storage().get_ban(id).await?.is_some())
+	}
+
+	pub async fn bans(&self) -> database::Result<Vec<Ban>> {
+		self.db.storage().bans().await
+	}
+
+	/// Bans the user, disconnecting their toys and logging them out everywhere.
+	/// Their Discord token is revoked and forgotten, so it isn't refreshed while they're banned.
+	pub async fn ban(
+		&self,
+		id: Id<UserMarker>,
+		admin: Id<UserMarker>,
+		reason: Option<String>,
+	) -> error::Result<()> {
+		let ban = Ban {
+			user_id: id.to_string(),
+			reason,
+			banned_by: admin.to_string(),
+			created_at: Utc::now().naive_utc(),
+		};
+		self.db.storage().ban(&ban).await?;
+		self.logout_all(&ban.user_id, true).await
+	}
+
+	/// Returns whether the user was banned.
+	pub async fn unban(&self, id: Id<UserMarker>) -> database::Result<bool> {
+		self.db.storage().unban(&id.to_string()).await
+	}
+}
@@ -1,15 +1,34 @@
-use std::env;
+use crate::config::OAuthConfig;
 
 use super::User;
 
 use log::warn;
-use serde::{Deserialize, Serialize};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{Response, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+	#[error("Couldn't reach Discord: {0}")]
+	Unreachable(#[source] reqwest::Error),
+	/// Discord refused the code or refresh token.
+	#[error("Discord rejected the grant ({status}): {body}")]
+	InvalidGrant { status: StatusCode, body: String },
+	#[error("Discord responded with {status}: {body}")]
+	Upstream { status: StatusCode, body: String },
+	#[error("Couldn't parse Discord's response: {0}")]
+	InvalidResponse(#[source] reqwest::Error),
+}
 
 pub struct Auth {
 	pub client: reqwest::Client,
 	client_id: String,
 	client_secret: String,
 	redirect_uri: String,
+	/// Whether to protect the login flow with PKCE on top of the state parameter.
+	pkce: bool,
 }
 
 #[derive(Serialize)]
@@ -19,6 +38,8 @@ pub struct ExchangeCode<'data> {
 	code: &'data str,
 	grant_type: &'static str,
 	redirect_uri: &'data str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	code_verifier: Option<&'data str>,
 }
 
 #[derive(Serialize)]
@@ -29,6 +50,14 @@ pub struct RefreshCode<'data> {
 	grant_type: &'static str,
 }
 
+#[derive(Serialize)]
+pub struct RevokeToken<'data> {
+	client_id: &'data str,
+	client_secret: &'data str,
+	token: &'data str,
+	token_type_hint: &'static str,
+}
+
 #[derive(Deserialize)]
 pub struct AccessToken {
 	pub access_token: String,
@@ -37,26 +66,87 @@ pub struct AccessToken {
 	pub refresh_token: String,
 }
 
+/// What the login flow has to remember between sending the user to Discord and getting the code back.
+pub struct LoginStart {
+	pub url: String,
+	pub state: String,
+	pub code_verifier: Option<String>,
+}
+
+pub fn random_string(len: usize) -> String {
+	rand::thread_rng()
+		.sample_iter(&Alphanumeric)
+		.take(len)
+		.map(char::from)
+		.collect()
+}
+
+fn code_challenge(verifier: &str) -> String {
+	base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Turns a response into `T`, or an error carrying what Discord said.
+async fn parse<T: DeserializeOwned>(res: Response) -> Result<T, AuthError> {
+	let status = res.status();
+	if status.is_success() {
+		return res.json::<T>().await.map_err(AuthError::InvalidResponse);
+	}
+	let body = res.text().await.unwrap_or_default();
+	warn!("Discord responded with {}: {}", status, body);
+	if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+		Err(AuthError::InvalidGrant { status, body })
+	} else {
+		Err(AuthError::Upstream { status, body })
+	}
+}
+
 impl Auth {
-	pub fn new() -> Self {
-		let client_id = env::var("CLIENT_ID").expect("CLIENT_ID not set");
-		let client_secret = env::var("CLIENT_SECRET").expect("CLIENT_SECRET not set");
-		let redirect_uri = env::var("REDIRECT_URI").expect("REDIRECT_URI not set");
+	pub fn new(config: &OAuthConfig) -> Self {
 		Auth {
 			client: reqwest::Client::new(),
-			client_id,
-			client_secret,
-			redirect_uri,
+			client_id: config.client_id.clone(),
+			client_secret: config.client_secret.clone(),
+			redirect_uri: config.redirect_uri.clone(),
+			pkce: config.pkce,
 		}
 	}
 
-	pub async fn exchange_code(&self, code: &str) -> Option<AccessToken> {
+	/// Builds the Discord authorize URL with a fresh state, and a PKCE verifier if enabled.
+	pub fn start_login(&self) -> LoginStart {
+		let state = random_string(32);
+		let code_verifier = self.pkce.then(|| random_string(64));
+		let mut params = vec![
+			("response_type", "code".to_owned()),
+			("client_id", self.client_id.clone()),
+			("scope", "identify".to_owned()),
+			("redirect_uri", self.redirect_uri.clone()),
+			("state", state.clone()),
+		];
+		if let Some(verifier) = &code_verifier {
+			params.push(("code_challenge", code_challenge(verifier)));
+			params.push(("code_challenge_method", "S256".to_owned()));
+		}
+		let url = Url::parse_with_params("https://discord.com/api/oauth2/authorize", &params)
+			.expect("Authorize URL should be valid");
+		LoginStart {
+			url: url.into(),
+			state,
+			code_verifier,
+		}
+	}
+
+	pub async fn exchange_code(
+		&self,
+		code: &str,
+		code_verifier: Option<&str>,
+	) -> Result<AccessToken, AuthError> {
 		let form = ExchangeCode {
 			client_id: &self.client_id,
 			client_secret: &self.client_secret,
 			code,
 			grant_type: "authorization_code",
 			redirect_uri: &self.redirect_uri,
+			code_verifier,
 		};
 		let res = self
 			.client
@@ -64,42 +154,79 @@ impl Auth {
 			.form(&form)
 			.send()
 			.await
-			.expect("Failed to exchange code");
-		if res.status().is_success() {
-			let token = res.json::<AccessToken>().await.unwrap();
-			Some(token)
-		} else {
-			warn!("Failed to exchange code: {}", res.status());
-			None
-		}
+			.map_err(AuthError::Unreachable)?;
+		parse(res).await
 	}
 
-	pub async fn refresh_token(&self, refresh_token: &str) -> AccessToken {
+	pub async fn refresh_token(&self, refresh_token: &str) -> Result<AccessToken, AuthError> {
 		let form = RefreshCode {
 			client_id: &self.client_id,
 			client_secret: &self.client_secret,
 			refresh_token,
 			grant_type: "refresh_token",
 		};
-		self.client
+		let res = self
+			.client
 			.post("https://discordapp.com/api/oauth2/token")
 			.form(&form)
 			.send()
 			.await
-			.expect("Failed to refresh token")
-			.json::<AccessToken>()
+			.map_err(AuthError::Unreachable)?;
+		parse(res).await
+	}
+
+	/// Revokes the refresh token, so no new access tokens can be made from it, then the access token.
+	/// Both are attempted, the first error is returned.
+	pub async fn revoke_tokens(
+		&self,
+		access_token: &str,
+		refresh_token: &str,
+	) -> Result<(), AuthError> {
+		let refresh = self.revoke(refresh_token, "refresh_token").await;
+		let access = self.revoke(access_token, "access_token").await;
+		refresh.and(access)
+	}
+
+	async fn revoke(&self, token: &str, token_type_hint: &'static str) -> Result<(), AuthError> {
+		let form = RevokeToken {
+			client_id: &self.client_id,
+			client_secret: &self.client_secret,
+			token,
+			token_type_hint,
+		};
+		let res = self
+			.client
+			.post("https://discord.com/api/oauth2/token/revoke")
+			.form(&form)
+			.send()
 			.await
-			.expect("Failed to parse access token")
+			.map_err(AuthError::Unreachable)?;
+		let status = res.status();
+		if status.is_success() {
+			Ok(())
+		} else {
+			let body = res.text().await.unwrap_or_default();
+			warn!(
+				"Failed to revoke {} ({}): {}",
+				token_type_hint, status, body
+			);
+			Err(AuthError::Upstream { status, body })
+		}
 	}
 
-	pub async fn get_user(&self, access_token: &str) -> User {
+	pub async fn get_user(&self, access_token: &str) -> Result<User, AuthError> {
 		let res = self
 			.client
 			.get("https://discordapp.com/api/users/@me")
 			.bearer_auth(access_token)
 			.send()
 			.await
-			.expect("Failed to get user");
-		res.json::<User>().await.expect("Failed to parse user")
+			.map_err(AuthError::Unreachable)?;
+		let mut user: User = parse(res).await?;
+		// Users on the new usernames come with a discriminator of "0"
+		if user.discriminator.as_deref() == Some("0") {
+			user.discriminator = None;
+		}
+		Ok(user)
 	}
 }
@@ -0,0 +1,108 @@
+use chacha20poly1305::{
+	aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+	XChaCha20Poly1305, XNonce,
+};
+use thiserror::Error;
+
+/// Marks a column value as encrypted, anything else is a token stored before encryption was set up.
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Error)]
+pub enum CipherError {
+	#[error("Token isn't valid base64")]
+	Encoding,
+	#[error("Token couldn't be decrypted with any configured key")]
+	Decrypt,
+}
+
+/// Decodes a base64 encoded 32 byte token key.
+pub fn decode_token_key(encoded: &str) -> Result<XChaCha20Poly1305, String> {
+	let bytes = base64::decode(encoded.trim()).map_err(|e| format!("not valid base64: {}", e))?;
+	XChaCha20Poly1305::new_from_slice(&bytes)
+		.map_err(|_| "must be exactly 32 bytes long".to_owned())
+}
+
+/// Encrypts OAuth tokens before they're stored, bound to the user and column they belong to,
+/// so they can't be swapped around in the database.
+pub struct TokenCipher {
+	primary: Option<XChaCha20Poly1305>,
+	previous: Vec<XChaCha20Poly1305>,
+}
+
+impl TokenCipher {
+	/// Without a primary key tokens are stored as they are.
+	pub fn new(primary: Option<XChaCha20Poly1305>, previous: Vec<XChaCha20Poly1305>) -> Self {
+		Self { primary, previous }
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.primary.is_some()
+	}
+
+	pub fn encrypt(&self, id: &str, column: &str, token: &str) -> String {
+		let key = match &self.primary {
+			Some(key) => key,
+			None => return token.to_owned(),
+		};
+		let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+		let aad = format!("{}:{}", id, column);
+		let ciphertext = key
+			.encrypt(
+				&nonce,
+				Payload {
+					msg: token.as_bytes(),
+					aad: aad.as_bytes(),
+				},
+			)
+			.expect("Encrypting a token can't fail");
+		let mut sealed = nonce.to_vec();
+		sealed.extend(ciphertext);
+		format!("{}{}", PREFIX, base64::encode(sealed))
+	}
+
+	/// Whether `stored` is encrypted with anything but the primary key, or not at all.
+	pub fn needs_reencrypt(&self, id: &str, column: &str, stored: &str) -> bool {
+		match (&self.primary, stored.strip_prefix(PREFIX)) {
+			(Some(key), Some(encoded)) => match base64::decode(encoded) {
+				Ok(sealed) => open(key, id, column, &sealed).is_none(),
+				Err(_) => true,
+			},
+			(Some(_), None) => true,
+			(None, _) => false,
+		}
+	}
+
+	/// Decrypts with whichever configured key the token was encrypted with.
+	/// Tokens stored before encryption was set up are returned as they are.
+	pub fn decrypt(&self, id: &str, column: &str, stored: &str) -> Result<String, CipherError> {
+		let encoded = match stored.strip_prefix(PREFIX) {
+			Some(encoded) => encoded,
+			None => return Ok(stored.to_owned()),
+		};
+		let sealed = base64::decode(encoded).map_err(|_| CipherError::Encoding)?;
+		self.primary
+			.iter()
+			.chain(&self.previous)
+			.find_map(|key| open(key, id, column, &sealed))
+			.ok_or(CipherError::Decrypt)
+	}
+}
+
+fn open(key: &XChaCha20Poly1305, id: &str, column: &str, sealed: &[u8]) -> Option<String> {
+	if sealed.len() < NONCE_LEN {
+		return None;
+	}
+	let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+	let aad = format!("{}:{}", id, column);
+	let plain = key
+		.decrypt(
+			XNonce::from_slice(nonce),
+			Payload {
+				msg: ciphertext,
+				aad: aad.as_bytes(),
+			},
+		)
+		.ok()?;
+	String::from_utf8(plain).ok()
+}
@@ -0,0 +1,290 @@
+mod cipher;
+mod postgres;
+mod sqlite;
+
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use log::warn;
+use sqlx::migrate::{AppliedMigration, MigrateError, Migration, Migrator};
+
+use crate::config::DatabaseConfig;
+
+use super::{
+	admin::{AuditEntry, AuditFilter, Ban, NewAuditEntry},
+	auth::AccessToken,
+	events::{Event, EventFilter, NewEvent},
+	stats::{GuildSettings, GuildStats, GuildStatsDelta, Ranked, Totals},
+	User,
+};
+
+pub use cipher::decode_token_key;
+use cipher::TokenCipher;
+
+pub type Result<T, E = sqlx::error::Error> = std::result::Result<T, E>;
+
+/// The OAuth tokens stored for a user.
+#[derive(sqlx::FromRow)]
+pub struct StoredToken {
+	pub id: String,
+	pub access_token: String,
+	pub refresh_token: String,
+	pub expires_at: NaiveDateTime,
+}
+
+/// Everything the bot keeps in a database, implemented once per backend.
+#[async_trait]
+pub trait Storage: Send + Sync {
+	/// Migrations the database hasn't had applied yet, oldest first.
+	async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, MigrateError>;
+	/// Applies every pending migration.
+	async fn run_migrations(&self) -> Result<(), MigrateError>;
+
+	async fn get_user(&self, id: &str) -> Result<Option<User>>;
+	async fn user_exists(&self, id: &str) -> Result<bool>;
+	async fn new_user(&self, user: &User, token: &StoredToken) -> Result<()>;
+	async fn update_profile(&self, user: &User) -> Result<()>;
+	async fn update_tokens(&self, token: &StoredToken) -> Result<()>;
+	/// Forgets a user's tokens, they'll have to log in again before they can be used.
+	async fn clear_tokens(&self, id: &str) -> Result<()>;
+	async fn get_token(&self, id: &str) -> Result<Option<StoredToken>>;
+	/// Whether the user's tokens were forgotten and they have to log in again.
+	async fn needs_login(&self, id: &str) -> Result<bool>;
+	/// Tokens that expire before `before`.
+	async fn expiring_tokens(&self, before: NaiveDateTime) -> Result<Vec<StoredToken>>;
+	async fn all_tokens(&self) -> Result<Vec<StoredToken>>;
+	/// Sessions are only valid while they carry the user's current epoch.
+	async fn session_epoch(&self, id: &str) -> Result<Option<i32>>;
+	/// Invalidates every session of the user.
+	async fn bump_session_epoch(&self, id: &str) -> Result<()>;
+
+	async fn insert_event(&self, event: &NewEvent) -> Result<()>;
+	/// Events that happened to `target`, newest first.
+	async fn events(&self, target: &str, filter: &EventFilter) -> Result<Vec<Event>>;
+	/// Every event that happened to `target`, oldest first.
+	async fn all_events(&self, target: &str) -> Result<Vec<Event>>;
+
+	/// Counts a trigger, and marks the toys as running since `at` unless they already were.
+	async fn add_trigger(&self, user: &str, power: f64, at: NaiveDateTime) -> Result<()>;
+	/// Adds the time since the toys started running to the user's active time.
+	async fn end_activity(&self, user: &str, at: NaiveDateTime) -> Result<()>;
+	async fn add_source(&self, user: &str, source: &str, power: f64) -> Result<()>;
+	async fn add_detail(&self, user: &str, kind: &str, detail: &str, power: f64) -> Result<()>;
+	async fn add_guild_stats(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		user: &str,
+		delta: &GuildStatsDelta,
+	) -> Result<()>;
+	async fn totals(&self, user: &str) -> Result<Option<Totals>>;
+	async fn top_sources(&self, user: &str, limit: i64) -> Result<Vec<Ranked>>;
+	async fn top_details(&self, user: &str, kind: &str, limit: i64) -> Result<Vec<Ranked>>;
+	async fn leaderboard_enabled(&self, guild_id: &str) -> Result<bool>;
+	async fn set_leaderboard(&self, guild_id: &str, enabled: bool) -> Result<()>;
+	async fn most_praised(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		limit: i64,
+	) -> Result<Vec<Ranked>>;
+	async fn best_flirts(&self, guild_id: &str, week: NaiveDate, limit: i64)
+		-> Result<Vec<Ranked>>;
+	/// The user's weekly stats in every guild.
+	async fn guild_stats(&self, user: &str) -> Result<Vec<GuildStats>>;
+
+	async fn guild_settings(&self) -> Result<Vec<GuildSettings>>;
+
+	async fn get_ban(&self, user: &str) -> Result<Option<Ban>>;
+	async fn bans(&self) -> Result<Vec<Ban>>;
+	/// Bans the user, replacing an earlier ban of them.
+	async fn ban(&self, ban: &Ban) -> Result<()>;
+	/// Returns whether the user was banned.
+	async fn unban(&self, user: &str) -> Result<bool>;
+	async fn insert_audit(&self, entry: &NewAuditEntry) -> Result<()>;
+	/// Audit log entries, newest first.
+	async fn audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>>;
+
+	/// Removes the user and everything tied to them, returning whether they existed.
+	/// Events they caused for others are kept, without saying who caused them.
+	/// Their session epoch is kept too, so a new account of theirs doesn't accept old sessions.
+	async fn delete_user(&self, id: &str) -> Result<bool>;
+}
+
+/// Up migrations of `migrator` that aren't in `applied`.
+fn pending(migrator: &'static Migrator, applied: Vec<AppliedMigration>) -> Vec<&'static Migration> {
+	let applied = applied
+		.into_iter()
+		.map(|migration| migration.version)
+		.collect::<HashSet<_>>();
+	migrator
+		.migrations
+		.iter()
+		.filter(|migration| !migration.migration_type.is_down_migration())
+		.filter(|migration| !applied.contains(&migration.version))
+		.collect()
+}
+
+/// The configured storage backend, picked by the scheme of the database URL.
+/// Tokens are encrypted on their way in and decrypted on their way out.
+pub struct EuphoriaDB {
+	storage: Box<dyn Storage>,
+	cipher: TokenCipher,
+}
+
+impl EuphoriaDB {
+	pub async fn new(config: &DatabaseConfig) -> Self {
+		let storage: Box<dyn Storage> = if config.url.starts_with("sqlite:") {
+			Box::new(
+				sqlite::SqliteStorage::connect(&config.url)
+					.await
+					.expect("Couldn't open the database"),
+			)
+		} else {
+			Box::new(
+				postgres::PgStorage::connect(&config.url)
+					.await
+					.expect("Couldn't connect to the database"),
+			)
+		};
+		let decode = |key: &String| decode_token_key(key).expect("Invalid token key");
+		let cipher = TokenCipher::new(
+			config.token_key.as_ref().map(decode),
+			config.previous_token_keys.iter().map(decode).collect(),
+		);
+		if !cipher.is_enabled() {
+			warn!("No token key configured, OAuth tokens are stored in plaintext");
+		}
+		Self { storage, cipher }
+	}
+
+	/// Everything that doesn't involve tokens, those only go through the methods here
+	/// so they're always encrypted.
+	pub fn storage(&self) -> &dyn Storage {
+		self.storage.as_ref()
+	}
+
+	/// Applies every pending migration, returning the ones that were applied.
+	pub async fn migrate(&self) -> Result<Vec<&'static Migration>, MigrateError> {
+		let pending = self.storage.pending_migrations().await?;
+		self.storage.run_migrations().await?;
+		Ok(pending)
+	}
+
+	pub async fn save_user(&self, user: &User, token: &AccessToken) -> Result<()> {
+		let token = self.seal(&user.id, token);
+		if self.storage.user_exists(&user.id).await? {
+			self.storage.update_profile(user).await?;
+			self.storage.update_tokens(&token).await
+		} else {
+			self.storage.new_user(user, &token).await
+		}
+	}
+
+	pub async fn update_tokens(&self, id: &str, token: &AccessToken) -> Result<()> {
+		self.storage.update_tokens(&self.seal(id, token)).await
+	}
+
+	/// A token that can't be decrypted, e.g. after its key was dropped, is forgotten
+	/// as if it was revoked, the user has to log in again.
+	pub async fn get_token(&self, id: &str) -> Result<Option<StoredToken>> {
+		let token = match self.storage.get_token(id).await? {
+			Some(token) => token,
+			None => return Ok(None),
+		};
+		match self.unseal(token) {
+			Ok(token) => Ok(Some(token)),
+			Err(e) => {
+				warn!("Forgetting token of {} that can't be decrypted: {}", id, e);
+				self.storage.clear_tokens(id).await?;
+				Ok(None)
+			}
+		}
+	}
+
+	/// Tokens that expire before `before`, leaving out the ones that can't be decrypted.
+	pub async fn expiring_tokens(&self, before: NaiveDateTime) -> Result<Vec<StoredToken>> {
+		let tokens = self.storage.expiring_tokens(before).await?;
+		Ok(tokens
+			.into_iter()
+			.filter_map(|token| {
+				let id = token.id.clone();
+				self.unseal(token)
+					.map_err(|e| warn!("Skipping token of {}: {}", id, e))
+					.ok()
+			})
+			.collect())
+	}
+
+	/// Encrypts every stored token with the primary key, returning how many were rewritten.
+	/// Run after rotating the key, before dropping the old one from the previous keys.
+	pub async fn reencrypt_tokens(&self) -> Result<usize> {
+		let mut count = 0;
+		for stored in self.storage.all_tokens().await? {
+			let outdated =
+				self.cipher
+					.needs_reencrypt(&stored.id, "access_token", &stored.access_token)
+					|| self.cipher.needs_reencrypt(
+						&stored.id,
+						"refresh_token",
+						&stored.refresh_token,
+					);
+			if !outdated {
+				continue;
+			}
+			let id = stored.id.clone();
+			let expires_at = stored.expires_at;
+			let token = match self.unseal(stored) {
+				Ok(token) => token,
+				Err(e) => {
+					warn!("Can't re-encrypt token of {}: {}", id, e);
+					continue;
+				}
+			};
+			let sealed = StoredToken {
+				access_token: self
+					.cipher
+					.encrypt(&id, "access_token", &token.access_token),
+				refresh_token: self
+					.cipher
+					.encrypt(&id, "refresh_token", &token.refresh_token),
+				expires_at,
+				id,
+			};
+			self.storage.update_tokens(&sealed).await?;
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	fn seal(&self, id: &str, token: &AccessToken) -> StoredToken {
+		StoredToken {
+			id: id.to_owned(),
+			access_token: self.cipher.encrypt(id, "access_token", &token.access_token),
+			refresh_token: self
+				.cipher
+				.encrypt(id, "refresh_token", &token.refresh_token),
+			expires_at: Utc::now().naive_local() + Duration::seconds(token.expires_in as i64),
+		}
+	}
+
+	fn unseal(&self, token: StoredToken) -> Result<StoredToken, cipher::CipherError> {
+		Ok(StoredToken {
+			access_token: self
+				.cipher
+				.decrypt(&token.id, "access_token", &token.access_token)?,
+			refresh_token: self
+				.cipher
+				.decrypt(&token.id, "refresh_token", &token.refresh_token)?,
+			..token
+		})
+	}
+}
+
+/// Whether `url` points at a database backend we support.
+pub fn supported_url(url: &str) -> bool {
+	["postgres://", "postgresql://", "sqlite:"]
+		.iter()
+		.any(|scheme| url.starts_with(scheme))
+}
@@ -0,0 +1,637 @@
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use sqlx::{
+	migrate::{Migrate, MigrateError, Migration, Migrator},
+	PgPool,
+};
+
+use crate::manager::{
+	admin::{AuditEntry, AuditFilter, Ban, NewAuditEntry},
+	events::{Event, EventFilter, NewEvent},
+	stats::{GuildSettings, GuildStats, GuildStatsDelta, Ranked, Totals},
+	User,
+};
+
+use super::{pending, Result, Storage, StoredToken};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+pub struct PgStorage {
+	pool: PgPool,
+}
+
+impl PgStorage {
+	pub async fn connect(url: &str) -> Result<Self> {
+		Ok(Self {
+			pool: PgPool::connect(url).await?,
+		})
+	}
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+	async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, MigrateError> {
+		let mut conn = self.pool.acquire().await?;
+		conn.ensure_migrations_table().await?;
+		Ok(pending(&MIGRATOR, conn.list_applied_migrations().await?))
+	}
+
+	async fn run_migrations(&self) -> Result<(), MigrateError> {
+		MIGRATOR.run(&self.pool).await
+	}
+
+	async fn get_user(&self, id: &str) -> Result<Option<User>> {
+		sqlx::query_as!(
+			User,
+			"SELECT id, username, global_name, discriminator, avatar FROM users WHERE id = $1",
+			id
+		)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn user_exists(&self, id: &str) -> Result<bool> {
+		sqlx::query!("SELECT EXISTS( SELECT 1 FROM users WHERE id = $1)", id)
+			.map(|r| r.exists.unwrap_or(false))
+			.fetch_one(&self.pool)
+			.await
+	}
+
+	async fn update_tokens(&self, token: &StoredToken) -> Result<()> {
+		sqlx::query!(
+			"
+			UPDATE users
+			SET
+				access_token = $1,
+				refresh_token = $2,
+				expires_at = $3,
+				needs_login = FALSE
+			WHERE id = $4
+			",
+			token.access_token,
+			token.refresh_token,
+			token.expires_at,
+			token.id
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn clear_tokens(&self, id: &str) -> Result<()> {
+		sqlx::query!(
+			"
+			UPDATE users
+			SET
+				access_token = NULL,
+				refresh_token = NULL,
+				expires_at = NULL,
+				needs_login = TRUE
+			WHERE id = $1
+			",
+			id
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn get_token(&self, id: &str) -> Result<Option<StoredToken>> {
+		sqlx::query_as!(
+			StoredToken,
+			r#"
+			SELECT
+				id,
+				access_token as "access_token!",
+				refresh_token as "refresh_token!",
+				expires_at as "expires_at!"
+			FROM users
+			WHERE id = $1
+				AND access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at IS NOT NULL
+			"#,
+			id
+		)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn needs_login(&self, id: &str) -> Result<bool> {
+		let needs_login = sqlx::query!("SELECT needs_login FROM users WHERE id = $1", id)
+			.map(|r| r.needs_login)
+			.fetch_optional(&self.pool)
+			.await?;
+		Ok(needs_login.unwrap_or(false))
+	}
+
+	async fn session_epoch(&self, id: &str) -> Result<Option<i32>> {
+		sqlx::query!("SELECT session_epoch FROM users WHERE id = $1", id)
+			.map(|r| r.session_epoch)
+			.fetch_optional(&self.pool)
+			.await
+	}
+
+	async fn bump_session_epoch(&self, id: &str) -> Result<()> {
+		sqlx::query!(
+			"UPDATE users SET session_epoch = session_epoch + 1 WHERE id = $1",
+			id
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn expiring_tokens(&self, before: NaiveDateTime) -> Result<Vec<StoredToken>> {
+		sqlx::query_as!(
+			StoredToken,
+			r#"
+			SELECT
+				id,
+				access_token as "access_token!",
+				refresh_token as "refresh_token!",
+				expires_at as "expires_at!"
+			FROM users
+			WHERE access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at < $1
+			"#,
+			before
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn all_tokens(&self) -> Result<Vec<StoredToken>> {
+		sqlx::query_as!(
+			StoredToken,
+			r#"
+			SELECT
+				id,
+				access_token as "access_token!",
+				refresh_token as "refresh_token!",
+				expires_at as "expires_at!"
+			FROM users
+			WHERE access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at IS NOT NULL
+			"#
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn new_user(&self, user: &User, token: &StoredToken) -> Result<()> {
+		sqlx::query!(
+			"INSERT INTO users
+				(id, username, global_name, discriminator, avatar, access_token, expires_at, refresh_token, session_epoch)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+				COALESCE((SELECT session_epoch FROM session_tombstones WHERE user_id = $1), 0))",
+			user.id,
+			user.username,
+			user.global_name,
+			user.discriminator,
+			user.avatar,
+			token.access_token,
+			token.expires_at,
+			token.refresh_token,
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn update_profile(&self, user: &User) -> Result<()> {
+		sqlx::query!(
+			"
+			UPDATE users
+			SET username = $1, global_name = $2, discriminator = $3, avatar = $4
+			WHERE id = $5
+			",
+			user.username,
+			user.global_name,
+			user.discriminator,
+			user.avatar,
+			user.id
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn insert_event(&self, event: &NewEvent) -> Result<()> {
+		sqlx::query!(
+			"INSERT INTO events
+				(target, source, guild_id, channel_id, kind, detail, power_delta, power, created_at)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+			event.target,
+			event.source,
+			event.guild_id,
+			event.channel_id,
+			event.kind.as_str(),
+			event.detail,
+			event.power_delta,
+			event.power,
+			event.created_at,
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn events(&self, target: &str, filter: &EventFilter) -> Result<Vec<Event>> {
+		sqlx::query_as!(
+			Event,
+			"
+			SELECT id, source, guild_id, channel_id, kind, detail, power_delta, power, created_at
+			FROM events
+			WHERE target = $1
+				AND ($2::TEXT IS NULL OR kind = $2)
+				AND ($3::TEXT IS NULL OR source = $3)
+				AND ($4::TEXT IS NULL OR guild_id = $4)
+				AND ($5::BIGINT IS NULL OR id < $5)
+			ORDER BY id DESC
+			LIMIT $6
+			",
+			target,
+			filter.kind.map(|kind| kind.as_str()),
+			filter.source,
+			filter.guild_id,
+			filter.before,
+			filter.limit(),
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn all_events(&self, target: &str) -> Result<Vec<Event>> {
+		sqlx::query_as!(
+			Event,
+			"
+			SELECT id, source, guild_id, channel_id, kind, detail, power_delta, power, created_at
+			FROM events
+			WHERE target = $1
+			ORDER BY id
+			",
+			target
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn add_trigger(&self, user: &str, power: f64, at: NaiveDateTime) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO user_stats (user_id, triggers, peak_power, active_since)
+			VALUES ($1, 1, $2, $3)
+			ON CONFLICT (user_id) DO UPDATE SET
+				triggers = user_stats.triggers + 1,
+				peak_power = GREATEST(user_stats.peak_power, EXCLUDED.peak_power),
+				active_since = COALESCE(user_stats.active_since, EXCLUDED.active_since)
+			",
+			user,
+			power,
+			at
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn end_activity(&self, user: &str, at: NaiveDateTime) -> Result<()> {
+		sqlx::query!(
+			"
+			UPDATE user_stats
+			SET
+				active_seconds = active_seconds
+					+ GREATEST(EXTRACT(EPOCH FROM ($2 - active_since))::DOUBLE PRECISION, 0),
+				active_since = NULL
+			WHERE user_id = $1 AND active_since IS NOT NULL
+			",
+			user,
+			at
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_source(&self, user: &str, source: &str, power: f64) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO user_sources (user_id, source, triggers, power)
+			VALUES ($1, $2, 1, $3)
+			ON CONFLICT (user_id, source) DO UPDATE SET
+				triggers = user_sources.triggers + 1,
+				power = user_sources.power + EXCLUDED.power
+			",
+			user,
+			source,
+			power
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_detail(&self, user: &str, kind: &str, detail: &str, power: f64) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO user_details (user_id, kind, detail, triggers, power)
+			VALUES ($1, $2, $3, 1, $4)
+			ON CONFLICT (user_id, kind, detail) DO UPDATE SET
+				triggers = user_details.triggers + 1,
+				power = user_details.power + EXCLUDED.power
+			",
+			user,
+			kind,
+			detail,
+			power
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_guild_stats(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		user: &str,
+		delta: &GuildStatsDelta,
+	) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO guild_stats
+				(guild_id, week, user_id, received_triggers, received, given_triggers, given)
+			VALUES ($1, $2, $3, $4, $5, $6, $7)
+			ON CONFLICT (guild_id, week, user_id) DO UPDATE SET
+				received_triggers = guild_stats.received_triggers + EXCLUDED.received_triggers,
+				received = guild_stats.received + EXCLUDED.received,
+				given_triggers = guild_stats.given_triggers + EXCLUDED.given_triggers,
+				given = guild_stats.given + EXCLUDED.given
+			",
+			guild_id,
+			week,
+			user,
+			delta.received_triggers,
+			delta.received,
+			delta.given_triggers,
+			delta.given
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn totals(&self, user: &str) -> Result<Option<Totals>> {
+		sqlx::query_as!(
+			Totals,
+			"SELECT triggers, peak_power, active_seconds FROM user_stats WHERE user_id = $1",
+			user
+		)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn top_sources(&self, user: &str, limit: i64) -> Result<Vec<Ranked>> {
+		sqlx::query_as!(
+			Ranked,
+			"
+			SELECT source AS id, triggers, power
+			FROM user_sources
+			WHERE user_id = $1
+			ORDER BY power DESC
+			LIMIT $2
+			",
+			user,
+			limit
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn top_details(&self, user: &str, kind: &str, limit: i64) -> Result<Vec<Ranked>> {
+		sqlx::query_as!(
+			Ranked,
+			"
+			SELECT detail AS id, triggers, power
+			FROM user_details
+			WHERE user_id = $1 AND kind = $2
+			ORDER BY power DESC
+			LIMIT $3
+			",
+			user,
+			kind,
+			limit
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn leaderboard_enabled(&self, guild_id: &str) -> Result<bool> {
+		let enabled = sqlx::query!(
+			"SELECT leaderboard FROM guild_settings WHERE guild_id = $1",
+			guild_id
+		)
+		.map(|r| r.leaderboard)
+		.fetch_optional(&self.pool)
+		.await?;
+		Ok(enabled.unwrap_or(false))
+	}
+
+	async fn set_leaderboard(&self, guild_id: &str, enabled: bool) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO guild_settings (guild_id, leaderboard)
+			VALUES ($1, $2)
+			ON CONFLICT (guild_id) DO UPDATE SET leaderboard = EXCLUDED.leaderboard
+			",
+			guild_id,
+			enabled
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn most_praised(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		limit: i64,
+	) -> Result<Vec<Ranked>> {
+		sqlx::query_as!(
+			Ranked,
+			"
+			SELECT user_id AS id, received_triggers AS triggers, received AS power
+			FROM guild_stats
+			WHERE guild_id = $1 AND week = $2 AND received_triggers > 0
+			ORDER BY received DESC
+			LIMIT $3
+			",
+			guild_id,
+			week,
+			limit
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn best_flirts(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		limit: i64,
+	) -> Result<Vec<Ranked>> {
+		sqlx::query_as!(
+			Ranked,
+			"
+			SELECT user_id AS id, given_triggers AS triggers, given AS power
+			FROM guild_stats
+			WHERE guild_id = $1 AND week = $2 AND given_triggers > 0
+			ORDER BY given DESC
+			LIMIT $3
+			",
+			guild_id,
+			week,
+			limit
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+	async fn guild_stats(&self, user: &str) -> Result<Vec<GuildStats>> {
+		sqlx::query_as!(
+			GuildStats,
+			"
+			SELECT guild_id, week, received_triggers, received, given_triggers, given
+			FROM guild_stats
+			WHERE user_id = $1
+			ORDER BY week, guild_id
+			",
+			user
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn guild_settings(&self) -> Result<Vec<GuildSettings>> {
+		sqlx::query_as!(
+			GuildSettings,
+			"SELECT guild_id, leaderboard FROM guild_settings ORDER BY guild_id"
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn get_ban(&self, user: &str) -> Result<Option<Ban>> {
+		sqlx::query_as!(
+			Ban,
+			"SELECT user_id, reason, banned_by, created_at FROM bans WHERE user_id = $1",
+			user
+		)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn bans(&self) -> Result<Vec<Ban>> {
+		sqlx::query_as!(
+			Ban,
+			"SELECT user_id, reason, banned_by, created_at FROM bans ORDER BY created_at DESC"
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn ban(&self, ban: &Ban) -> Result<()> {
+		sqlx::query!(
+			"
+			INSERT INTO bans (user_id, reason, banned_by, created_at)
+			VALUES ($1, $2, $3, $4)
+			ON CONFLICT (user_id) DO UPDATE SET
+				reason = EXCLUDED.reason,
+				banned_by = EXCLUDED.banned_by,
+				created_at = EXCLUDED.created_at
+			",
+			ban.user_id,
+			ban.reason,
+			ban.banned_by,
+			ban.created_at
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn unban(&self, user: &str) -> Result<bool> {
+		let deleted = sqlx::query!("DELETE FROM bans WHERE user_id = $1", user)
+			.execute(&self.pool)
+			.await?
+			.rows_affected();
+		Ok(deleted > 0)
+	}
+
+	async fn insert_audit(&self, entry: &NewAuditEntry) -> Result<()> {
+		sqlx::query!(
+			"INSERT INTO admin_audit (admin_id, action, target, detail, outcome, created_at)
+			VALUES ($1, $2, $3, $4, $5, $6)",
+			entry.admin_id,
+			entry.action.as_str(),
+			entry.target,
+			entry.detail,
+			entry.outcome.as_str(),
+			entry.created_at,
+		)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+		sqlx::query_as!(
+			AuditEntry,
+			"
+			SELECT id, admin_id, action, target, detail, outcome, created_at
+			FROM admin_audit
+			WHERE ($1::BIGINT IS NULL OR id < $1)
+			ORDER BY id DESC
+			LIMIT $2
+			",
+			filter.before,
+			filter.limit(),
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn delete_user(&self, id: &str) -> Result<bool> {
+		let mut tx = self.pool.begin().await?;
+		sqlx::query!("UPDATE events SET source = NULL WHERE source = $1", id)
+			.execute(&mut tx)
+			.await?;
+		sqlx::query!("DELETE FROM user_sources WHERE source = $1", id)
+			.execute(&mut tx)
+			.await?;
+		sqlx::query!("DELETE FROM guild_stats WHERE user_id = $1", id)
+			.execute(&mut tx)
+			.await?;
+		// Sessions of the deleted user mustn't become valid again if they come back
+		sqlx::query!(
+			"
+			INSERT INTO session_tombstones (user_id, session_epoch)
+			SELECT id, session_epoch + 1 FROM users WHERE id = $1
+			ON CONFLICT (user_id) DO UPDATE SET session_epoch = EXCLUDED.session_epoch
+			",
+			id
+		)
+		.execute(&mut tx)
+		.await?;
+		// Stats and events of the user go with it
+		let deleted = sqlx::query!("DELETE FROM users WHERE id = $1", id)
+			.execute(&mut tx)
+			.await?
+			.rows_affected();
+		tx.commit().await?;
+		Ok(deleted > 0)
+	}
+}
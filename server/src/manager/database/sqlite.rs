@@ -0,0 +1,617 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use sqlx::{
+	migrate::{Migrate, MigrateError, Migration, Migrator},
+	sqlite::SqliteConnectOptions,
+	SqlitePool,
+};
+
+use crate::manager::{
+	admin::{AuditEntry, AuditFilter, Ban, NewAuditEntry},
+	events::{Event, EventFilter, NewEvent},
+	stats::{GuildSettings, GuildStats, GuildStatsDelta, Ranked, Totals},
+	User,
+};
+
+use super::{pending, Result, Storage, StoredToken};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Queries are checked at runtime here, the query macros can only check against one database.
+pub struct SqliteStorage {
+	pool: SqlitePool,
+}
+
+impl SqliteStorage {
+	/// Opens the database file at `url`, creating it if it doesn't exist yet.
+	pub async fn connect(url: &str) -> Result<Self> {
+		let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+		Ok(Self {
+			pool: SqlitePool::connect_with(options).await?,
+		})
+	}
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+	async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, MigrateError> {
+		let mut conn = self.pool.acquire().await?;
+		conn.ensure_migrations_table().await?;
+		Ok(pending(&MIGRATOR, conn.list_applied_migrations().await?))
+	}
+
+	async fn run_migrations(&self) -> Result<(), MigrateError> {
+		MIGRATOR.run(&self.pool).await
+	}
+
+	async fn get_user(&self, id: &str) -> Result<Option<User>> {
+		// No avatar is stored as an empty string, see the profile migration
+		sqlx::query_as(
+			"
+			SELECT id, username, global_name, discriminator, NULLIF(avatar, '') AS avatar
+			FROM users
+			WHERE id = ?
+			",
+		)
+		.bind(id)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn user_exists(&self, id: &str) -> Result<bool> {
+		sqlx::query_scalar("SELECT EXISTS( SELECT 1 FROM users WHERE id = ?)")
+			.bind(id)
+			.fetch_one(&self.pool)
+			.await
+	}
+
+	async fn update_tokens(&self, token: &StoredToken) -> Result<()> {
+		sqlx::query(
+			"
+			UPDATE users
+			SET
+				access_token = ?,
+				refresh_token = ?,
+				expires_at = ?,
+				needs_login = FALSE
+			WHERE id = ?
+			",
+		)
+		.bind(&token.access_token)
+		.bind(&token.refresh_token)
+		.bind(token.expires_at)
+		.bind(&token.id)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn clear_tokens(&self, id: &str) -> Result<()> {
+		sqlx::query(
+			"
+			UPDATE users
+			SET
+				access_token = NULL,
+				refresh_token = NULL,
+				expires_at = NULL,
+				needs_login = TRUE
+			WHERE id = ?
+			",
+		)
+		.bind(id)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn get_token(&self, id: &str) -> Result<Option<StoredToken>> {
+		sqlx::query_as(
+			"
+			SELECT id, access_token, refresh_token, expires_at
+			FROM users
+			WHERE id = ?
+				AND access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at IS NOT NULL
+			",
+		)
+		.bind(id)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn needs_login(&self, id: &str) -> Result<bool> {
+		let needs_login = sqlx::query_scalar("SELECT needs_login FROM users WHERE id = ?")
+			.bind(id)
+			.fetch_optional(&self.pool)
+			.await?;
+		Ok(needs_login.unwrap_or(false))
+	}
+
+	async fn session_epoch(&self, id: &str) -> Result<Option<i32>> {
+		sqlx::query_scalar("SELECT session_epoch FROM users WHERE id = ?")
+			.bind(id)
+			.fetch_optional(&self.pool)
+			.await
+	}
+
+	async fn bump_session_epoch(&self, id: &str) -> Result<()> {
+		sqlx::query("UPDATE users SET session_epoch = session_epoch + 1 WHERE id = ?")
+			.bind(id)
+			.execute(&self.pool)
+			.await?;
+		Ok(())
+	}
+
+	async fn expiring_tokens(&self, before: NaiveDateTime) -> Result<Vec<StoredToken>> {
+		sqlx::query_as(
+			"
+			SELECT id, access_token, refresh_token, expires_at
+			FROM users
+			WHERE access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at < ?
+			",
+		)
+		.bind(before)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn all_tokens(&self) -> Result<Vec<StoredToken>> {
+		sqlx::query_as(
+			"
+			SELECT id, access_token, refresh_token, expires_at
+			FROM users
+			WHERE access_token IS NOT NULL
+				AND refresh_token IS NOT NULL
+				AND expires_at IS NOT NULL
+			",
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn new_user(&self, user: &User, token: &StoredToken) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO users
+				(id, username, global_name, discriminator, avatar, access_token, expires_at, refresh_token, session_epoch)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8,
+				COALESCE((SELECT session_epoch FROM session_tombstones WHERE user_id = ?1), 0))",
+		)
+		.bind(&user.id)
+		.bind(&user.username)
+		.bind(&user.global_name)
+		.bind(&user.discriminator)
+		.bind(user.avatar.as_deref().unwrap_or(""))
+		.bind(&token.access_token)
+		.bind(token.expires_at)
+		.bind(&token.refresh_token)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn update_profile(&self, user: &User) -> Result<()> {
+		sqlx::query(
+			"
+			UPDATE users
+			SET username = ?, global_name = ?, discriminator = ?, avatar = ?
+			WHERE id = ?
+			",
+		)
+		.bind(&user.username)
+		.bind(&user.global_name)
+		.bind(&user.discriminator)
+		.bind(user.avatar.as_deref().unwrap_or(""))
+		.bind(&user.id)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn insert_event(&self, event: &NewEvent) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO events
+				(target, source, guild_id, channel_id, kind, detail, power_delta, power, created_at)
+			VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+		)
+		.bind(&event.target)
+		.bind(&event.source)
+		.bind(&event.guild_id)
+		.bind(&event.channel_id)
+		.bind(event.kind.as_str())
+		.bind(&event.detail)
+		.bind(event.power_delta)
+		.bind(event.power)
+		.bind(event.created_at)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn events(&self, target: &str, filter: &EventFilter) -> Result<Vec<Event>> {
+		sqlx::query_as(
+			"
+			SELECT id, source, guild_id, channel_id, kind, detail, power_delta, power, created_at
+			FROM events
+			WHERE target = ?1
+				AND (?2 IS NULL OR kind = ?2)
+				AND (?3 IS NULL OR source = ?3)
+				AND (?4 IS NULL OR guild_id = ?4)
+				AND (?5 IS NULL OR id < ?5)
+			ORDER BY id DESC
+			LIMIT ?6
+			",
+		)
+		.bind(target)
+		.bind(filter.kind.map(|kind| kind.as_str()))
+		.bind(&filter.source)
+		.bind(&filter.guild_id)
+		.bind(filter.before)
+		.bind(filter.limit())
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn all_events(&self, target: &str) -> Result<Vec<Event>> {
+		sqlx::query_as(
+			"
+			SELECT id, source, guild_id, channel_id, kind, detail, power_delta, power, created_at
+			FROM events
+			WHERE target = ?
+			ORDER BY id
+			",
+		)
+		.bind(target)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn add_trigger(&self, user: &str, power: f64, at: NaiveDateTime) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO user_stats (user_id, triggers, peak_power, active_since)
+			VALUES (?, 1, ?, ?)
+			ON CONFLICT (user_id) DO UPDATE SET
+				triggers = user_stats.triggers + 1,
+				peak_power = MAX(user_stats.peak_power, excluded.peak_power),
+				active_since = COALESCE(user_stats.active_since, excluded.active_since)
+			",
+		)
+		.bind(user)
+		.bind(power)
+		.bind(at)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn end_activity(&self, user: &str, at: NaiveDateTime) -> Result<()> {
+		sqlx::query(
+			"
+			UPDATE user_stats
+			SET
+				active_seconds = active_seconds
+					+ MAX((julianday(?2) - julianday(active_since)) * 86400, 0),
+				active_since = NULL
+			WHERE user_id = ?1 AND active_since IS NOT NULL
+			",
+		)
+		.bind(user)
+		.bind(at)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_source(&self, user: &str, source: &str, power: f64) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO user_sources (user_id, source, triggers, power)
+			VALUES (?, ?, 1, ?)
+			ON CONFLICT (user_id, source) DO UPDATE SET
+				triggers = user_sources.triggers + 1,
+				power = user_sources.power + excluded.power
+			",
+		)
+		.bind(user)
+		.bind(source)
+		.bind(power)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_detail(&self, user: &str, kind: &str, detail: &str, power: f64) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO user_details (user_id, kind, detail, triggers, power)
+			VALUES (?, ?, ?, 1, ?)
+			ON CONFLICT (user_id, kind, detail) DO UPDATE SET
+				triggers = user_details.triggers + 1,
+				power = user_details.power + excluded.power
+			",
+		)
+		.bind(user)
+		.bind(kind)
+		.bind(detail)
+		.bind(power)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn add_guild_stats(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		user: &str,
+		delta: &GuildStatsDelta,
+	) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO guild_stats
+				(guild_id, week, user_id, received_triggers, received, given_triggers, given)
+			VALUES (?, ?, ?, ?, ?, ?, ?)
+			ON CONFLICT (guild_id, week, user_id) DO UPDATE SET
+				received_triggers = guild_stats.received_triggers + excluded.received_triggers,
+				received = guild_stats.received + excluded.received,
+				given_triggers = guild_stats.given_triggers + excluded.given_triggers,
+				given = guild_stats.given + excluded.given
+			",
+		)
+		.bind(guild_id)
+		.bind(week)
+		.bind(user)
+		.bind(delta.received_triggers)
+		.bind(delta.received)
+		.bind(delta.given_triggers)
+		.bind(delta.given)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn totals(&self, user: &str) -> Result<Option<Totals>> {
+		sqlx::query_as(
+			"SELECT triggers, peak_power, active_seconds FROM user_stats WHERE user_id = ?",
+		)
+		.bind(user)
+		.fetch_optional(&self.pool)
+		.await
+	}
+
+	async fn top_sources(&self, user: &str, limit: i64) -> Result<Vec<Ranked>> {
+		sqlx::query_as(
+			"
+			SELECT source AS id, triggers, power
+			FROM user_sources
+			WHERE user_id = ?
+			ORDER BY power DESC
+			LIMIT ?
+			",
+		)
+		.bind(user)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn top_details(&self, user: &str, kind: &str, limit: i64) -> Result<Vec<Ranked>> {
+		sqlx::query_as(
+			"
+			SELECT detail AS id, triggers, power
+			FROM user_details
+			WHERE user_id = ? AND kind = ?
+			ORDER BY power DESC
+			LIMIT ?
+			",
+		)
+		.bind(user)
+		.bind(kind)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn leaderboard_enabled(&self, guild_id: &str) -> Result<bool> {
+		let enabled: Option<bool> =
+			sqlx::query_scalar("SELECT leaderboard FROM guild_settings WHERE guild_id = ?")
+				.bind(guild_id)
+				.fetch_optional(&self.pool)
+				.await?;
+		Ok(enabled.unwrap_or(false))
+	}
+
+	async fn set_leaderboard(&self, guild_id: &str, enabled: bool) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO guild_settings (guild_id, leaderboard)
+			VALUES (?, ?)
+			ON CONFLICT (guild_id) DO UPDATE SET leaderboard = excluded.leaderboard
+			",
+		)
+		.bind(guild_id)
+		.bind(enabled)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn most_praised(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		limit: i64,
+	) -> Result<Vec<Ranked>> {
+		sqlx::query_as(
+			"
+			SELECT user_id AS id, received_triggers AS triggers, received AS power
+			FROM guild_stats
+			WHERE guild_id = ? AND week = ? AND received_triggers > 0
+			ORDER BY received DESC
+			LIMIT ?
+			",
+		)
+		.bind(guild_id)
+		.bind(week)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn best_flirts(
+		&self,
+		guild_id: &str,
+		week: NaiveDate,
+		limit: i64,
+	) -> Result<Vec<Ranked>> {
+		sqlx::query_as(
+			"
+			SELECT user_id AS id, given_triggers AS triggers, given AS power
+			FROM guild_stats
+			WHERE guild_id = ? AND week = ? AND given_triggers > 0
+			ORDER BY given DESC
+			LIMIT ?
+			",
+		)
+		.bind(guild_id)
+		.bind(week)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await
+	}
+	async fn guild_stats(&self, user: &str) -> Result<Vec<GuildStats>> {
+		sqlx::query_as(
+			"
+			SELECT guild_id, week, received_triggers, received, given_triggers, given
+			FROM guild_stats
+			WHERE user_id = ?
+			ORDER BY week, guild_id
+			",
+		)
+		.bind(user)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn guild_settings(&self) -> Result<Vec<GuildSettings>> {
+		sqlx::query_as("SELECT guild_id, leaderboard FROM guild_settings ORDER BY guild_id")
+			.fetch_all(&self.pool)
+			.await
+	}
+
+	async fn get_ban(&self, user: &str) -> Result<Option<Ban>> {
+		sqlx::query_as("SELECT user_id, reason, banned_by, created_at FROM bans WHERE user_id = ?")
+			.bind(user)
+			.fetch_optional(&self.pool)
+			.await
+	}
+
+	async fn bans(&self) -> Result<Vec<Ban>> {
+		sqlx::query_as(
+			"SELECT user_id, reason, banned_by, created_at FROM bans ORDER BY created_at DESC",
+		)
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn ban(&self, ban: &Ban) -> Result<()> {
+		sqlx::query(
+			"
+			INSERT INTO bans (user_id, reason, banned_by, created_at)
+			VALUES (?, ?, ?, ?)
+			ON CONFLICT (user_id) DO UPDATE SET
+				reason = excluded.reason,
+				banned_by = excluded.banned_by,
+				created_at = excluded.created_at
+			",
+		)
+		.bind(&ban.user_id)
+		.bind(&ban.reason)
+		.bind(&ban.banned_by)
+		.bind(ban.created_at)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn unban(&self, user: &str) -> Result<bool> {
+		let deleted = sqlx::query("DELETE FROM bans WHERE user_id = ?")
+			.bind(user)
+			.execute(&self.pool)
+			.await?
+			.rows_affected();
+		Ok(deleted > 0)
+	}
+
+	async fn insert_audit(&self, entry: &NewAuditEntry) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO admin_audit (admin_id, action, target, detail, outcome, created_at)
+			VALUES (?, ?, ?, ?, ?, ?)",
+		)
+		.bind(&entry.admin_id)
+		.bind(entry.action.as_str())
+		.bind(&entry.target)
+		.bind(&entry.detail)
+		.bind(entry.outcome.as_str())
+		.bind(entry.created_at)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	async fn audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>> {
+		sqlx::query_as(
+			"
+			SELECT id, admin_id, action, target, detail, outcome, created_at
+			FROM admin_audit
+			WHERE (?1 IS NULL OR id < ?1)
+			ORDER BY id DESC
+			LIMIT ?2
+			",
+		)
+		.bind(filter.before)
+		.bind(filter.limit())
+		.fetch_all(&self.pool)
+		.await
+	}
+
+	async fn delete_user(&self, id: &str) -> Result<bool> {
+		let mut tx = self.pool.begin().await?;
+		sqlx::query("UPDATE events SET source = NULL WHERE source = ?")
+			.bind(id)
+			.execute(&mut tx)
+			.await?;
+		sqlx::query("DELETE FROM user_sources WHERE source = ?")
+			.bind(id)
+			.execute(&mut tx)
+			.await?;
+		sqlx::query("DELETE FROM guild_stats WHERE user_id = ?")
+			.bind(id)
+			.execute(&mut tx)
+			.await?;
+		// Sessions of the deleted user mustn't become valid again if they come back
+		sqlx::query(
+			"
+			INSERT INTO session_tombstones (user_id, session_epoch)
+			SELECT id, session_epoch + 1 FROM users WHERE id = ?
+			ON CONFLICT (user_id) DO UPDATE SET session_epoch = excluded.session_epoch
+			",
+		)
+		.bind(id)
+		.execute(&mut tx)
+		.await?;
+		// Stats and events of the user go with it
+		let deleted = sqlx::query("DELETE FROM users WHERE id = ?")
+			.bind(id)
+			.execute(&mut tx)
+			.await?
+			.rows_affected();
+		tx.commit().await?;
+		Ok(deleted > 0)
+	}
+}
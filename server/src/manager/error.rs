@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+use super::AuthError;
+
+pub type Result<T, E = ManagerError> = std::result::Result<T, E>;
+
+/// What can go wrong in the manager, the server decides what each means for a response.
+#[derive(Debug, Error)]
+pub enum ManagerError {
+	#[error("Sqlx error: {0}")]
+	Database(#[from] sqlx::Error),
+	/// Discord refused the code the user logged in with.
+	#[error("Discord rejected the auth code")]
+	InvalidCode,
+	#[error("Discord auth error: {0}")]
+	Auth(#[from] AuthError),
+	#[error("User is banned")]
+	Banned,
+	#[error("Discord API error: {0}")]
+	Discord(#[from] twilight_http::Error),
+}
@@ -0,0 +1,234 @@
+use std::{
+	fmt::{self, Display},
+	str::FromStr,
+	sync::{Arc, Mutex},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use futures::FutureExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	select,
+	sync::{
+		mpsc::{self, UnboundedReceiver, UnboundedSender},
+		Notify,
+	},
+};
+use twilight_model::id::{
+	marker::{ChannelMarker, GuildMarker, UserMarker},
+	Id,
+};
+
+use crate::user::PowerChange;
+
+use super::{stats, Manager};
+
+/// Default and maximum amount of events per history page.
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+	/// A flirty message.
+	Message,
+	Reaction,
+	/// A reaction was taken back.
+	Unreact,
+	/// The toys were stopped.
+	Stop,
+	/// A trigger the throttle didn't let through.
+	Throttled,
+}
+
+impl EventKind {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			EventKind::Message => "message",
+			EventKind::Reaction => "reaction",
+			EventKind::Unreact => "unreact",
+			EventKind::Stop => "stop",
+			EventKind::Throttled => "throttled",
+		}
+	}
+}
+
+impl Display for EventKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+impl FromStr for EventKind {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"message" => Ok(EventKind::Message),
+			"reaction" => Ok(EventKind::Reaction),
+			"unreact" => Ok(EventKind::Unreact),
+			"stop" => Ok(EventKind::Stop),
+			"throttled" => Ok(EventKind::Throttled),
+			other => Err(format!("Unknown event kind: {}", other)),
+		}
+	}
+}
+
+/// Something that happened to a user's toys, waiting to be written.
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+	pub target: String,
+	pub source: Option<String>,
+	pub guild_id: Option<String>,
+	pub channel_id: Option<String>,
+	pub kind: EventKind,
+	/// The matched phrase or emoji.
+	pub detail: Option<String>,
+	pub power_delta: f64,
+	/// Power after the event.
+	pub power: f64,
+	pub created_at: NaiveDateTime,
+}
+
+impl NewEvent {
+	pub fn new(target: Id<UserMarker>, kind: EventKind, change: PowerChange) -> Self {
+		Self {
+			target: target.to_string(),
+			source: None,
+			guild_id: None,
+			channel_id: None,
+			kind,
+			detail: None,
+			power_delta: change.delta,
+			power: change.power,
+			created_at: Utc::now().naive_utc(),
+		}
+	}
+
+	/// Who caused the event, and where.
+	pub fn source(
+		mut self,
+		source: Option<Id<UserMarker>>,
+		guild_id: Option<Id<GuildMarker>>,
+		channel_id: Id<ChannelMarker>,
+	) -> Self {
+		self.source = source.map(|id| id.to_string());
+		self.guild_id = guild_id.map(|id| id.to_string());
+		self.channel_id = Some(channel_id.to_string());
+		self
+	}
+
+	pub fn detail(mut self, detail: impl Into<String>) -> Self {
+		self.detail = Some(detail.into());
+		self
+	}
+
+	/// When the event happened, if that wasn't just now.
+	pub fn at(mut self, created_at: NaiveDateTime) -> Self {
+		self.created_at = created_at;
+		self
+	}
+}
+
+/// A stored event.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Event {
+	pub id: i64,
+	pub source: Option<String>,
+	pub guild_id: Option<String>,
+	pub channel_id: Option<String>,
+	pub kind: String,
+	pub detail: Option<String>,
+	pub power_delta: f64,
+	pub power: f64,
+	pub created_at: NaiveDateTime,
+}
+
+/// Which events to return, newest first.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventFilter {
+	pub kind: Option<EventKind>,
+	pub source: Option<String>,
+	pub guild_id: Option<String>,
+	/// Only events older than this event id, for the next page.
+	pub before: Option<i64>,
+	pub limit: Option<i64>,
+}
+
+impl EventFilter {
+	pub fn limit(&self) -> i64 {
+		self.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE)
+	}
+}
+
+/// Cheap handle to queue events with, events are written in the background.
+#[derive(Clone)]
+pub struct EventSender(UnboundedSender<NewEvent>);
+
+impl EventSender {
+	pub fn record(&self, event: NewEvent) {
+		if self.0.send(event).is_err() {
+			warn!("Event writer is gone, dropping event");
+		}
+	}
+}
+
+pub struct EventLog {
+	sender: EventSender,
+	receiver: Mutex<Option<UnboundedReceiver<NewEvent>>>,
+}
+
+impl Default for EventLog {
+	fn default() -> Self {
+		let (sender, receiver) = mpsc::unbounded_channel();
+		Self {
+			sender: EventSender(sender),
+			receiver: Mutex::new(Some(receiver)),
+		}
+	}
+}
+
+impl EventLog {
+	pub fn record(&self, event: NewEvent) {
+		self.sender.record(event);
+	}
+
+	pub fn sender(&self) -> EventSender {
+		self.sender.clone()
+	}
+}
+
+/// Writes queued events until shutdown, then writes whatever is left.
+pub async fn run_event_writer(manager: Arc<Manager>, notify_term: Arc<Notify>) {
+	let mut receiver = match manager.events.receiver.lock().unwrap().take() {
+		Some(receiver) => receiver,
+		None => {
+			error!("Event writer is already running");
+			return;
+		}
+	};
+	loop {
+		select! {
+			Some(event) = receiver.recv() => write(&manager, event).await,
+			_ = notify_term.notified().fuse() => break,
+		}
+	}
+	receiver.close();
+	while let Some(event) = receiver.recv().await {
+		write(&manager, event).await;
+	}
+}
+
+async fn write(manager: &Manager, event: NewEvent) {
+	if let Err(e) = manager.db.storage().insert_event(&event).await {
+		error!(
+			"Failed to record {} event of {}: {}",
+			event.kind, event.target, e
+		);
+		return;
+	}
+	if let Err(e) = stats::aggregate(manager.db.storage(), &event).await {
+		error!("Failed to update stats of {}: {}", event.target, e);
+	}
+}
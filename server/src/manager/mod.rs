@@ -1,60 +1,287 @@
+use std::{collections::HashSet, sync::Arc};
+
 use actix::Addr;
+use dashmap::DashMap;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use twilight_model::id::{marker::UserMarker, Id};
+use twilight_model::{
+	id::{marker::UserMarker, Id},
+	util::ImageHash,
+};
 
-use crate::user::ButtplugUser;
+use crate::{
+	config::{Config, DatabaseConfig},
+	user::{ButtplugUser, Disconnect},
+};
 
+pub mod account;
+pub mod admin;
 mod auth;
-mod database;
-mod users;
+pub mod database;
+pub mod error;
+pub mod events;
+pub mod shards;
+pub mod stats;
+pub mod throttle;
+mod tokens;
+pub mod users;
+
+pub use auth::{AuthError, LoginStart};
+pub use error::ManagerError;
+pub use events::run_event_writer;
+pub use tokens::run_token_refresh;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
 pub struct User {
 	pub id: String,
 	pub username: String,
-	pub avatar: String,
+	/// Display name, if the user picked one.
+	#[serde(default)]
+	pub global_name: Option<String>,
+	/// Only users who haven't moved to the new usernames have one.
+	#[serde(default)]
+	pub discriminator: Option<String>,
+	pub avatar: Option<String>,
 }
 
 pub struct Manager {
 	pub auth: auth::Auth,
 	pub db: database::EuphoriaDB,
 	pub user_manager: users::UserManager,
+	pub throttle: throttle::Throttle,
+	pub shards: shards::ShardStatuses,
+	pub events: events::EventLog,
+	/// Discord's API as the bot, shared with it so they're rate limited together.
+	pub discord: Arc<twilight_http::Client>,
+	/// Operators allowed to use the admin API.
+	admins: HashSet<Id<UserMarker>>,
+	refresh_locks: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
 }
 
 impl Manager {
-	pub async fn new() -> Self {
+	pub async fn new(config: &Config) -> Self {
 		Self {
-			auth: auth::Auth::new(),
-			db: database::EuphoriaDB::new().await,
+			auth: auth::Auth::new(&config.oauth),
+			db: database::EuphoriaDB::new(&config.database).await,
 			user_manager: Default::default(),
+			throttle: throttle::Throttle::new(config.throttle),
+			shards: Default::default(),
+			events: Default::default(),
+			discord: Arc::new(twilight_http::Client::new(config.discord.token.clone())),
+			admins: config.admin.ids().into_iter().collect(),
+			refresh_locks: Default::default(),
 		}
 	}
 }
 
+async fn apply_migrations(db: &database::EuphoriaDB) -> anyhow::Result<()> {
+	let applied = db.migrate().await?;
+	if applied.is_empty() {
+		info!("Database schema is up to date");
+	}
+	for migration in applied {
+		info!(
+			"Applied migration {} {}",
+			migration.version, migration.description
+		);
+	}
+	Ok(())
+}
+
+/// Applies pending migrations without setting up anything besides the database, for `--migrate-only`.
+pub async fn migrate_only(config: &DatabaseConfig) -> anyhow::Result<()> {
+	apply_migrations(&database::EuphoriaDB::new(config).await).await
+}
+
+/// database impls
+impl Manager {
+	/// Brings the schema up to date, logging every migration that was applied.
+	pub async fn migrate(&self) -> anyhow::Result<()> {
+		apply_migrations(&self.db).await
+	}
+
+	/// Encrypts every stored token with the current token key.
+	pub async fn reencrypt_tokens(&self) -> anyhow::Result<()> {
+		let count = self.db.reencrypt_tokens().await?;
+		info!("Re-encrypted the tokens of {} users", count);
+		Ok(())
+	}
+
+	/// Makes sure the schema is current before anything touches it, migrating it if `migrate` is set.
+	pub async fn prepare_database(&self, migrate: bool) -> anyhow::Result<()> {
+		if migrate {
+			return self.migrate().await;
+		}
+		let pending = self.db.storage().pending_migrations().await?;
+		if !pending.is_empty() {
+			let names = pending
+				.iter()
+				.map(|migration| format!("{} {}", migration.version, migration.description))
+				.collect::<Vec<_>>()
+				.join(", ");
+			anyhow::bail!(
+				"Database schema is behind, run with --migrate-only first. Pending: {}",
+				names
+			);
+		}
+		Ok(())
+	}
+}
+
 /// auth impls
 impl Manager {
-	pub async fn login(&self, code: &str) -> database::Result<Option<User>> {
-		let token = match self.auth.exchange_code(code).await {
-			Some(token) => token,
-			None => return Ok(None),
-		};
-		let user = self.auth.get_user(&token.access_token).await;
+	/// Exchanges an OAuth code for tokens and saves the user they belong to.
+	/// A code Discord refuses becomes [`ManagerError::InvalidCode`].
+	pub async fn login(&self, code: &str, code_verifier: Option<&str>) -> error::Result<User> {
+		let token = self
+			.auth
+			.exchange_code(code, code_verifier)
+			.await
+			.map_err(|e| match e {
+				AuthError::InvalidGrant { .. } => ManagerError::InvalidCode,
+				e => ManagerError::Auth(e),
+			})?;
+		let user = self.auth.get_user(&token.access_token).await?;
+		if self.is_banned(&user.id).await? {
+			return Err(ManagerError::Banned);
+		}
 		self.db.save_user(&user, &token).await?;
-		Ok(Some(user))
+		Ok(user)
+	}
+
+	pub fn start_login(&self) -> LoginStart {
+		self.auth.start_login()
 	}
 
 	pub async fn get_user(&self, id: &str) -> database::Result<Option<User>> {
-		self.db.get_user(id).await
+		self.db.storage().get_user(id).await
+	}
+
+	/// Updates a stored profile with what the gateway says about the user.
+	/// The gateway doesn't know about display names, those are only refreshed on login.
+	pub async fn refresh_profile(
+		&self,
+		id: Id<UserMarker>,
+		username: &str,
+		discriminator: u16,
+		avatar: Option<ImageHash>,
+	) -> database::Result<()> {
+		let mut user = match self.db.storage().get_user(&id.to_string()).await? {
+			Some(user) => user,
+			None => return Ok(()),
+		};
+		let discriminator = (discriminator != 0).then(|| format!("{:04}", discriminator));
+		let avatar = avatar.map(|hash| hash.to_string());
+		if user.username == username && user.discriminator == discriminator && user.avatar == avatar
+		{
+			return Ok(());
+		}
+		user.username = username.to_owned();
+		user.discriminator = discriminator;
+		user.avatar = avatar;
+		self.db.storage().update_profile(&user).await?;
+		Ok(())
+	}
+
+	/// Whether the user's Discord token was revoked or refused, so they have to log in again.
+	pub async fn needs_login(&self, id: &str) -> database::Result<bool> {
+		self.db.storage().needs_login(id).await
+	}
+
+	pub async fn session_epoch(&self, id: &str) -> database::Result<Option<i32>> {
+		self.db.storage().session_epoch(id).await
+	}
+
+	/// Disconnects the user's toys, and revokes and forgets their Discord token if asked to.
+	pub async fn logout(&self, id: &str, revoke: bool) -> error::Result<()> {
+		if let Ok(user_id) = id.parse::<Id<UserMarker>>() {
+			self.disconnect_all(user_id);
+		}
+		if revoke {
+			// The tokens are forgotten either way, Discord expires them eventually
+			if let Some(token) = self.db.get_token(id).await? {
+				if let Err(e) = self
+					.auth
+					.revoke_tokens(&token.access_token, &token.refresh_token)
+					.await
+				{
+					warn!("Failed to revoke token of {}: {}", id, e);
+				}
+			}
+			self.db.storage().clear_tokens(id).await?;
+		}
+		Ok(())
+	}
+
+	/// Logs the user out everywhere, existing sessions stop being accepted.
+	/// They aren't deleted from the session store, which can't be searched by user,
+	/// but are purged the next time they're used and otherwise expire with their TTL.
+	pub async fn logout_all(&self, id: &str, revoke: bool) -> error::Result<()> {
+		self.db.storage().bump_session_epoch(id).await?;
+		self.logout(id, revoke).await
 	}
 }
 
 /// user impls
 impl Manager {
-	pub fn insert(&self, id: Id<UserMarker>, addr: Addr<ButtplugUser>) {
-		self.user_manager.insert(id, addr);
+	pub fn register(&self, id: Id<UserMarker>) -> users::Registration {
+		self.user_manager.register(id)
+	}
+
+	pub fn insert(
+		&self,
+		registration: &users::Registration,
+		name: String,
+		addr: Addr<ButtplugUser>,
+	) {
+		self.user_manager.insert(registration, name, addr);
 	}
 
-	pub fn get(&self, id: Id<UserMarker>) -> Option<Addr<ButtplugUser>> {
+	pub fn get(&self, id: Id<UserMarker>) -> Option<users::UserConnections> {
 		self.user_manager.get(id)
 	}
+
+	pub fn connections(&self, id: Id<UserMarker>) -> Vec<users::ConnectionInfo> {
+		self.user_manager.list(id)
+	}
+
+	pub async fn connection_status(&self, id: Id<UserMarker>) -> Vec<users::ConnectionStatus> {
+		self.user_manager.status(id).await
+	}
+
+	/// Disconnects one of the user's connections, returning whether it existed.
+	pub fn disconnect(&self, id: Id<UserMarker>, name: &str) -> bool {
+		match self.user_manager.remove(id, name) {
+			Some(connection) => {
+				connection.addr.do_send(Disconnect);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Disconnects every connection of the user, returning whether there were any.
+	pub fn disconnect_all(&self, id: Id<UserMarker>) -> bool {
+		let connections = self.user_manager.remove_all(id);
+		for connection in &connections {
+			connection.addr.do_send(Disconnect);
+		}
+		!connections.is_empty()
+	}
+
+	pub async fn connection_stats(&self) -> users::ConnectionStats {
+		self.user_manager.stats().await
+	}
+
+	pub fn throttled(&self, id: Id<UserMarker>) -> Vec<throttle::ThrottledEvent> {
+		self.throttle.throttled(id)
+	}
+
+	pub async fn history(
+		&self,
+		id: Id<UserMarker>,
+		filter: &events::EventFilter,
+	) -> database::Result<Vec<events::Event>> {
+		self.db.storage().events(&id.to_string(), filter).await
+	}
 }
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShardStatus {
+	pub id: u64,
+	/// Gateway connection stage, e.g. `Connected` or `Resuming`.
+	pub stage: String,
+	pub latency_ms: Option<u128>,
+	/// Events received since startup.
+	pub events: u64,
+}
+
+/// Status of every gateway shard the bot runs, shared with the web server.
+#[derive(Default)]
+pub struct ShardStatuses {
+	shards: DashMap<u64, ShardStatus>,
+}
+
+impl ShardStatuses {
+	pub fn count_event(&self, id: u64) {
+		self.shards
+			.entry(id)
+			.or_insert_with(|| ShardStatus {
+				id,
+				..Default::default()
+			})
+			.events += 1;
+	}
+
+	pub fn update(&self, id: u64, stage: String, latency: Option<Duration>) {
+		let mut status = self.shards.entry(id).or_insert_with(|| ShardStatus {
+			id,
+			..Default::default()
+		});
+		status.stage = stage;
+		status.latency_ms = latency.map(|latency| latency.as_millis());
+	}
+
+	pub fn all(&self) -> Vec<ShardStatus> {
+		let mut shards = self
+			.shards
+			.iter()
+			.map(|entry| entry.value().clone())
+			.collect::<Vec<_>>();
+		shards.sort_by_key(|status| status.id);
+		shards
+	}
+}
@@ -0,0 +1,194 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use serde::Serialize;
+use twilight_http::error::ErrorType;
+use twilight_model::id::{
+	marker::{GuildMarker, UserMarker},
+	Id,
+};
+
+use super::{
+	database, error,
+	events::{EventKind, NewEvent},
+	Manager,
+};
+
+/// Entries in every top list.
+const TOP: i64 = 10;
+
+/// A user's running totals.
+#[derive(Debug, Default, Serialize, sqlx::FromRow)]
+pub struct Totals {
+	pub triggers: i64,
+	pub peak_power: f64,
+	/// Time the toys were running, up to the last time they stopped.
+	pub active_seconds: f64,
+}
+
+/// A place in a top list: a user, phrase or emoji, how often it triggered and how much power it gave.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Ranked {
+	pub id: String,
+	pub triggers: i64,
+	pub power: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserStats {
+	#[serde(flatten)]
+	pub totals: Totals,
+	pub top_flirters: Vec<Ranked>,
+	pub top_phrases: Vec<Ranked>,
+	pub top_emojis: Vec<Ranked>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Leaderboard {
+	pub week: NaiveDate,
+	/// Whoever received the most power.
+	pub most_praised: Vec<Ranked>,
+	/// Whoever gave the most power.
+	pub best_flirts: Vec<Ranked>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct GuildSettings {
+	pub guild_id: String,
+	pub leaderboard: bool,
+}
+
+/// A user's stats in a guild for one week.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct GuildStats {
+	pub guild_id: String,
+	pub week: NaiveDate,
+	pub received_triggers: i64,
+	pub received: f64,
+	pub given_triggers: i64,
+	pub given: f64,
+}
+
+/// Changes to a user's weekly guild stats.
+#[derive(Debug, Default)]
+pub struct GuildStatsDelta {
+	pub received_triggers: i64,
+	pub received: f64,
+	pub given_triggers: i64,
+	pub given: f64,
+}
+
+/// Monday of the week `at` falls in, guild stats are kept per week.
+pub fn week_of(at: NaiveDateTime) -> NaiveDate {
+	let date = at.date();
+	date - Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+/// Folds an event into the aggregates, so reading stats never has to go through the history.
+pub async fn aggregate(db: &dyn database::Storage, event: &NewEvent) -> database::Result<()> {
+	match event.kind {
+		EventKind::Message | EventKind::Reaction => {
+			// Running from now on, unless they already were
+			db.add_trigger(&event.target, event.power, event.created_at)
+				.await?;
+			if let Some(source) = &event.source {
+				db.add_source(&event.target, source, event.power_delta)
+					.await?;
+			}
+			if let Some(detail) = &event.detail {
+				db.add_detail(
+					&event.target,
+					event.kind.as_str(),
+					detail,
+					event.power_delta,
+				)
+				.await?;
+			}
+			if let (Some(guild_id), Some(source)) = (&event.guild_id, &event.source) {
+				let week = week_of(event.created_at);
+				let received = GuildStatsDelta {
+					received_triggers: 1,
+					received: event.power_delta,
+					..Default::default()
+				};
+				db.add_guild_stats(guild_id, week, &event.target, &received)
+					.await?;
+				let given = GuildStatsDelta {
+					given_triggers: 1,
+					given: event.power_delta,
+					..Default::default()
+				};
+				db.add_guild_stats(guild_id, week, source, &given).await?;
+			}
+		}
+		EventKind::Stop => db.end_activity(&event.target, event.created_at).await?,
+		// Counts only go up, taken back reactions are still counted
+		EventKind::Unreact | EventKind::Throttled => {}
+	}
+	Ok(())
+}
+
+/// stats impls
+impl Manager {
+	pub async fn stats(&self, id: Id<UserMarker>) -> database::Result<UserStats> {
+		let id = id.to_string();
+		Ok(UserStats {
+			totals: self.db.storage().totals(&id).await?.unwrap_or_default(),
+			top_flirters: self.db.storage().top_sources(&id, TOP).await?,
+			top_phrases: self
+				.db
+				.storage()
+				.top_details(&id, EventKind::Message.as_str(), TOP)
+				.await?,
+			top_emojis: self
+				.db
+				.storage()
+				.top_details(&id, EventKind::Reaction.as_str(), TOP)
+				.await?,
+		})
+	}
+
+	/// Whether the user is in the guild, asked from Discord so leaving counts right away.
+	pub async fn is_member(
+		&self,
+		guild_id: Id<GuildMarker>,
+		user_id: Id<UserMarker>,
+	) -> error::Result<bool> {
+		match self.discord.guild_member(guild_id, user_id).await {
+			Ok(_) => Ok(true),
+			Err(e) => match e.kind() {
+				// Unknown member, or a guild the bot isn't in
+				ErrorType::Response { status, .. } if matches!(status.get(), 403 | 404) => {
+					Ok(false)
+				}
+				_ => Err(e.into()),
+			},
+		}
+	}
+
+	/// This week's leaderboard, `None` unless the guild opted in.
+	pub async fn leaderboard(
+		&self,
+		guild_id: Id<GuildMarker>,
+	) -> database::Result<Option<Leaderboard>> {
+		let guild_id = guild_id.to_string();
+		if !self.db.storage().leaderboard_enabled(&guild_id).await? {
+			return Ok(None);
+		}
+		let week = week_of(Utc::now().naive_utc());
+		Ok(Some(Leaderboard {
+			week,
+			most_praised: self.db.storage().most_praised(&guild_id, week, TOP).await?,
+			best_flirts: self.db.storage().best_flirts(&guild_id, week, TOP).await?,
+		}))
+	}
+
+	pub async fn set_leaderboard(
+		&self,
+		guild_id: Id<GuildMarker>,
+		enabled: bool,
+	) -> database::Result<()> {
+		self.db
+			.storage()
+			.set_leaderboard(&guild_id.to_string(), enabled)
+			.await
+	}
+}
@@ -0,0 +1,310 @@
+use std::{
+	collections::{hash_map::DefaultHasher, VecDeque},
+	hash::{Hash, Hasher},
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{marker::UserMarker, Id};
+
+use crate::config::seconds;
+
+/// Below this weight a repeated message isn't worth sending at all.
+const MIN_WEIGHT: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleSettings {
+	/// Minimum time between two triggers from the same sender to the same target.
+	#[serde(with = "seconds")]
+	pub sender_cooldown: Duration,
+	/// Maximum amount of triggers a target may receive inside `target_window`.
+	pub target_cap: usize,
+	#[serde(with = "seconds")]
+	pub target_window: Duration,
+	/// Multiplier applied to a message identical to the sender's last one.
+	/// Compounds with every repeat.
+	pub repeat_falloff: f64,
+	/// After this long without triggering, a sender's repeat count is forgotten.
+	#[serde(with = "seconds")]
+	pub repeat_reset: Duration,
+	/// Amount of throttled events remembered per target.
+	pub history: usize,
+}
+
+impl Default for ThrottleSettings {
+	fn default() -> Self {
+		Self {
+			sender_cooldown: Duration::from_secs(2),
+			target_cap: 20,
+			target_window: Duration::from_secs(10),
+			repeat_falloff: 0.5,
+			repeat_reset: Duration::from_secs(300),
+			history: 50,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerKind {
+	Message,
+	Reaction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleReason {
+	/// The sender triggered the same target too recently.
+	Cooldown,
+	/// The target has been triggered too often by everyone combined.
+	TargetCap,
+	/// The sender kept repeating the same message.
+	Repeated,
+}
+
+impl ThrottleReason {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			ThrottleReason::Cooldown => "cooldown",
+			ThrottleReason::TargetCap => "target_cap",
+			ThrottleReason::Repeated => "repeated",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThrottledEvent {
+	pub sender: Id<UserMarker>,
+	pub kind: TriggerKind,
+	pub reason: ThrottleReason,
+	pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+	/// The trigger goes through, scaled by the given weight.
+	Allow(f64),
+	Throttled(ThrottleReason),
+}
+
+#[derive(Default)]
+struct SenderState {
+	last: Option<Instant>,
+	last_content: Option<u64>,
+	repeats: i32,
+}
+
+/// Rate limiting of triggers, both per sender and target pair and per target.
+pub struct Throttle {
+	settings: ThrottleSettings,
+	senders: DashMap<(Id<UserMarker>, Id<UserMarker>), SenderState>,
+	targets: DashMap<Id<UserMarker>, VecDeque<Instant>>,
+	throttled: DashMap<Id<UserMarker>, VecDeque<ThrottledEvent>>,
+}
+
+fn content_hash(content: &str) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	content.trim().to_lowercase().hash(&mut hasher);
+	hasher.finish()
+}
+
+impl Throttle {
+	pub fn new(settings: ThrottleSettings) -> Self {
+		Self {
+			settings,
+			senders: Default::default(),
+			targets: Default::default(),
+			throttled: Default::default(),
+		}
+	}
+
+	/// Decides whether `sender` may trigger `target` right now, and records the trigger if so.
+	/// `content` is the message text, used to detect repeated messages.
+	pub fn check(
+		&self,
+		sender: Id<UserMarker>,
+		target: Id<UserMarker>,
+		kind: TriggerKind,
+		content: Option<&str>,
+	) -> Verdict {
+		let verdict = self.check_inner(sender, target, content.map(content_hash));
+		if let Verdict::Throttled(reason) = verdict {
+			self.record(target, sender, kind, reason);
+		}
+		verdict
+	}
+
+	fn check_inner(
+		&self,
+		sender: Id<UserMarker>,
+		target: Id<UserMarker>,
+		content: Option<u64>,
+	) -> Verdict {
+		let now = Instant::now();
+		let mut state = self.senders.entry((sender, target)).or_default();
+
+		if let Some(last) = state.last {
+			let since = now.duration_since(last);
+			if since < self.settings.sender_cooldown {
+				return Verdict::Throttled(ThrottleReason::Cooldown);
+			}
+			if since > self.settings.repeat_reset {
+				state.last_content = None;
+				state.repeats = 0;
+			}
+		}
+
+		let repeats = match (content, state.last_content) {
+			(Some(content), Some(last)) if content == last => state.repeats + 1,
+			_ => 0,
+		};
+		let weight = self.settings.repeat_falloff.powi(repeats);
+		if weight < MIN_WEIGHT {
+			state.last = Some(now);
+			return Verdict::Throttled(ThrottleReason::Repeated);
+		}
+
+		let mut hits = self.targets.entry(target).or_default();
+		while let Some(hit) = hits.front() {
+			if now.duration_since(*hit) > self.settings.target_window {
+				hits.pop_front();
+			} else {
+				break;
+			}
+		}
+		if hits.len() >= self.settings.target_cap {
+			return Verdict::Throttled(ThrottleReason::TargetCap);
+		}
+		hits.push_back(now);
+
+		state.last = Some(now);
+		state.last_content = content;
+		state.repeats = repeats;
+		Verdict::Allow(weight)
+	}
+
+	fn record(
+		&self,
+		target: Id<UserMarker>,
+		sender: Id<UserMarker>,
+		kind: TriggerKind,
+		reason: ThrottleReason,
+	) {
+		let mut events = self.throttled.entry(target).or_default();
+		if events.len() >= self.settings.history {
+			events.pop_front();
+		}
+		events.push_back(ThrottledEvent {
+			sender,
+			kind,
+			reason,
+			at: Utc::now(),
+		});
+	}
+
+	/// Recently throttled triggers aimed at `target`, oldest first.
+	pub fn throttled(&self, target: Id<UserMarker>) -> Vec<ThrottledEvent> {
+		self.throttled
+			.get(&target)
+			.map(|events| events.iter().cloned().collect())
+			.unwrap_or_default()
+	}
+
+	/// Forgets senders that have been quiet long enough to no longer matter.
+	pub fn prune(&self) {
+		let now = Instant::now();
+		let settings = self.settings;
+		self.senders.retain(|_, state| match state.last {
+			Some(last) => now.duration_since(last) < settings.repeat_reset,
+			None => false,
+		});
+		self.targets.retain(|_, hits| match hits.back() {
+			Some(last) => now.duration_since(*last) < settings.target_window,
+			None => false,
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread::sleep;
+
+	use super::*;
+
+	const TARGET: Id<UserMarker> = Id::new(1);
+	const OTHER_TARGET: Id<UserMarker> = Id::new(2);
+	const SENDER: Id<UserMarker> = Id::new(10);
+	const OTHER_SENDER: Id<UserMarker> = Id::new(11);
+
+	fn throttle(settings: ThrottleSettings) -> Throttle {
+		Throttle::new(ThrottleSettings {
+			repeat_falloff: 1.0,
+			..settings
+		})
+	}
+
+	fn react(throttle: &Throttle, sender: Id<UserMarker>, target: Id<UserMarker>) -> Verdict {
+		throttle.check(sender, target, TriggerKind::Reaction, None)
+	}
+
+	#[test]
+	fn sender_waits_out_the_cooldown_per_target() {
+		let throttle = throttle(ThrottleSettings {
+			sender_cooldown: Duration::from_millis(50),
+			..Default::default()
+		});
+		assert_eq!(react(&throttle, SENDER, TARGET), Verdict::Allow(1.0));
+		assert_eq!(
+			react(&throttle, SENDER, TARGET),
+			Verdict::Throttled(ThrottleReason::Cooldown)
+		);
+		// The cooldown only holds for this sender and target
+		assert_eq!(react(&throttle, OTHER_SENDER, TARGET), Verdict::Allow(1.0));
+		assert_eq!(react(&throttle, SENDER, OTHER_TARGET), Verdict::Allow(1.0));
+
+		sleep(Duration::from_millis(60));
+		assert_eq!(react(&throttle, SENDER, TARGET), Verdict::Allow(1.0));
+	}
+
+	#[test]
+	fn target_cap_counts_everyone_inside_the_window() {
+		let throttle = throttle(ThrottleSettings {
+			sender_cooldown: Duration::ZERO,
+			target_cap: 2,
+			target_window: Duration::from_millis(50),
+			..Default::default()
+		});
+		assert_eq!(react(&throttle, SENDER, TARGET), Verdict::Allow(1.0));
+		assert_eq!(react(&throttle, OTHER_SENDER, TARGET), Verdict::Allow(1.0));
+		assert_eq!(
+			react(&throttle, SENDER, TARGET),
+			Verdict::Throttled(ThrottleReason::TargetCap)
+		);
+		// Other targets have their own window
+		assert_eq!(react(&throttle, SENDER, OTHER_TARGET), Verdict::Allow(1.0));
+
+		sleep(Duration::from_millis(60));
+		assert_eq!(react(&throttle, SENDER, TARGET), Verdict::Allow(1.0));
+	}
+
+	#[test]
+	fn throttled_triggers_are_remembered_per_target() {
+		let throttle = throttle(ThrottleSettings {
+			history: 1,
+			..Default::default()
+		});
+		react(&throttle, SENDER, TARGET);
+		react(&throttle, SENDER, TARGET);
+		throttle.check(SENDER, TARGET, TriggerKind::Message, Some("good girl"));
+
+		let throttled = throttle.throttled(TARGET);
+		assert_eq!(throttled.len(), 1);
+		assert_eq!(throttled[0].sender, SENDER);
+		assert_eq!(throttled[0].kind, TriggerKind::Message);
+		assert_eq!(throttled[0].reason, ThrottleReason::Cooldown);
+		assert!(throttle.throttled(OTHER_TARGET).is_empty());
+	}
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use futures::FutureExt;
+use log::{error, info, warn};
+use tokio::{select, sync::Notify};
+
+use super::{auth::AuthError, database::StoredToken, error, Manager};
+
+/// Tokens expiring sooner than this many hours get refreshed.
+const REFRESH_MARGIN_HOURS: i64 = 24;
+
+/// How often the background task looks for expiring tokens.
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+fn expires_soon(token: &StoredToken) -> bool {
+	token.expires_at - Utc::now().naive_local() < Duration::hours(REFRESH_MARGIN_HOURS)
+}
+
+impl Manager {
+	/// A valid Discord access token for the user, refreshed first if it's about to expire.
+	/// `None` if the user has no tokens, or Discord no longer accepts them.
+	pub async fn access_token(&self, id: &str) -> error::Result<Option<String>> {
+		match self.db.get_token(id).await? {
+			Some(token) if !expires_soon(&token) => Ok(Some(token.access_token)),
+			Some(_) => self.refresh(id).await,
+			None => Ok(None),
+		}
+	}
+
+	async fn refresh(&self, id: &str) -> error::Result<Option<String>> {
+		// Refresh tokens are single use, so two refreshes at once would log the user out
+		let lock = self.refresh_locks.entry(id.to_owned()).or_default().clone();
+		let refreshed = {
+			let _guard = lock.lock().await;
+			self.refresh_locked(id).await
+		};
+		drop(lock);
+		// Only the map holds the lock when nobody else is refreshing, it's made again next time
+		self.refresh_locks
+			.remove_if(id, |_, lock| Arc::strong_count(lock) == 1);
+		refreshed
+	}
+
+	/// Refreshes the user's tokens, holding their refresh lock.
+	async fn refresh_locked(&self, id: &str) -> error::Result<Option<String>> {
+		let stored = match self.db.get_token(id).await? {
+			Some(token) if expires_soon(&token) => token,
+			Some(token) => return Ok(Some(token.access_token)),
+			None => return Ok(None),
+		};
+		match self.auth.refresh_token(&stored.refresh_token).await {
+			Ok(token) => {
+				self.db.update_tokens(id, &token).await?;
+				Ok(Some(token.access_token))
+			}
+			Err(AuthError::InvalidGrant { .. }) => {
+				warn!("Discord rejected the refresh token of {}, clearing it", id);
+				self.db.storage().clear_tokens(id).await?;
+				Ok(None)
+			}
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Refreshes every token that is about to expire.
+	pub async fn refresh_expiring(&self) -> error::Result<()> {
+		let before = Utc::now().naive_local() + Duration::hours(REFRESH_MARGIN_HOURS);
+		let tokens = self.db.expiring_tokens(before).await?;
+		if !tokens.is_empty() {
+			info!("Refreshing {} expiring tokens", tokens.len());
+		}
+		for token in tokens {
+			if let Err(e) = self.refresh(&token.id).await {
+				error!("Failed to refresh token of {}: {}", token.id, e);
+			}
+		}
+		Ok(())
+	}
+}
+
+pub async fn run_token_refresh(manager: Arc<Manager>, notify_term: Arc<Notify>) {
+	let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+	loop {
+		select! {
+			_ = interval.tick() => {
+				if let Err(e) = manager.refresh_expiring().await {
+					error!("Failed to look for expiring tokens: {}", e);
+				}
+			}
+			_ = notify_term.notified().fuse() => break,
+		}
+	}
+}
@@ -1,20 +1,308 @@
-use actix::Addr;
+use std::{
+	collections::HashSet,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use actix::{dev::ToEnvelope, Actor, Addr, Handler, MailboxError, Message};
+use chrono::{NaiveDateTime, Utc};
 use dashmap::DashMap;
+use futures::future::join_all;
+use log::warn;
+use serde::Serialize;
+use tokio::time::timeout;
 use twilight_model::id::{marker::UserMarker, Id};
 
-use crate::user::ButtplugUser;
+use crate::user::{ButtplugUser, Disconnect, GetPower, GetStatus, Status};
+
+/// How long a user actor gets to answer before it's considered unresponsive.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Name of the connection when the client doesn't pick one.
+pub const DEFAULT_CONNECTION: &str = "default";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+	/// Users with a buttplug connection.
+	pub connected: usize,
+	/// Connected users whose toys are currently running.
+	pub active: usize,
+}
+
+type ConnectionMap = DashMap<Id<UserMarker>, Vec<Connection>>;
+
+/// Connections of a user whose toys are running.
+#[derive(Default)]
+struct Running {
+	connections: HashSet<u64>,
+	/// Latest time a connection stopped while others kept running.
+	last_stop: Option<NaiveDateTime>,
+}
+
+type RunningMap = DashMap<Id<UserMarker>, Running>;
+
+/// One buttplug connection of a user, e.g. their phone or their desktop.
+#[derive(Clone)]
+pub struct Connection {
+	/// Unique for the lifetime of the process, unlike the name.
+	id: u64,
+	pub name: String,
+	pub connected_at: NaiveDateTime,
+	pub addr: Addr<ButtplugUser>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+	pub name: String,
+	pub connected_at: NaiveDateTime,
+}
+
+impl From<&Connection> for ConnectionInfo {
+	fn from(connection: &Connection) -> Self {
+		Self {
+			name: connection.name.clone(),
+			connected_at: connection.connected_at,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatus {
+	#[serde(flatten)]
+	pub info: ConnectionInfo,
+	/// Whether the connection answered in time, the rest is left out if it didn't.
+	pub responsive: bool,
+	#[serde(flatten)]
+	pub status: Option<Status>,
+}
+
+/// Every connection of a user, triggers go to all of them.
+#[derive(Clone)]
+pub struct UserConnections(Vec<Connection>);
+
+impl UserConnections {
+	/// Sends `msg` to every connection, answering with what the oldest connection that answered said.
+	pub async fn send<M>(&self, msg: M) -> Result<M::Result, MailboxError>
+	where
+		M: Message + Clone + Send + 'static,
+		M::Result: Send,
+		ButtplugUser: Handler<M>,
+		<ButtplugUser as Actor>::Context: ToEnvelope<ButtplugUser, M>,
+	{
+		let answers = join_all(
+			self.0
+				.iter()
+				.map(|connection| connection.addr.send(msg.clone())),
+		)
+		.await;
+		let mut first = None;
+		for (connection, answer) in self.0.iter().zip(answers) {
+			match answer {
+				Ok(answer) => {
+					if !matches!(first, Some(Ok(_))) {
+						first = Some(Ok(answer));
+					}
+				}
+				Err(e) => {
+					warn!("Connection {} didn't answer: {}", connection.name, e);
+					first.get_or_insert(Err(e));
+				}
+			}
+		}
+		first.unwrap_or(Err(MailboxError::Closed))
+	}
+	/// Sends `msg` to every connection without waiting for answers.
+	pub fn do_send<M>(&self, msg: M)
+	where
+		M: Message + Clone + Send + 'static,
+		M::Result: Send,
+		ButtplugUser: Handler<M>,
+		<ButtplugUser as Actor>::Context: ToEnvelope<ButtplugUser, M>,
+	{
+		for connection in &self.0 {
+			connection.addr.do_send(msg.clone());
+		}
+	}
+}
+
+/// Ties an actor to the connection it was registered as,
+/// so it can take itself out of the user manager when it stops.
+#[derive(Clone)]
+pub struct Registration {
+	map: Arc<ConnectionMap>,
+	running: Arc<RunningMap>,
+	user: Id<UserMarker>,
+	connection: u64,
+}
+
+impl Registration {
+	/// Removes the connection, unless it has been replaced by a newer one already.
+	pub fn release(&self) {
+		if let Some(mut connections) = self.map.get_mut(&self.user) {
+			connections.retain(|connection| connection.id != self.connection);
+		}
+		self.map
+			.remove_if(&self.user, |_, connections| connections.is_empty());
+	}
+
+	/// Marks the connection's toys as running.
+	pub fn start(&self) {
+		let mut running = self.running.entry(self.user).or_default();
+		if running.connections.is_empty() {
+			running.last_stop = None;
+		}
+		running.connections.insert(self.connection);
+	}
+
+	/// Marks the connection's toys as stopped at `at`.
+	/// Answers when the user's toys stopped, if this was the last connection running them.
+	pub fn stop(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+		let last_stop = {
+			let mut running = self.running.get_mut(&self.user)?;
+			if !running.connections.remove(&self.connection) {
+				return None;
+			}
+			let last_stop = running.last_stop.map_or(at, |last_stop| last_stop.max(at));
+			if !running.connections.is_empty() {
+				running.last_stop = Some(last_stop);
+				return None;
+			}
+			last_stop
+		};
+		self.running
+			.remove_if(&self.user, |_, running| running.connections.is_empty());
+		Some(last_stop)
+	}
+}
 
 #[derive(Default)]
 pub struct UserManager {
-	map: DashMap<Id<UserMarker>, Addr<ButtplugUser>>,
+	map: Arc<ConnectionMap>,
+	running: Arc<RunningMap>,
+	next_id: AtomicU64,
 }
 
 impl UserManager {
-	pub fn insert(&self, id: Id<UserMarker>, addr: Addr<ButtplugUser>) {
-		self.map.insert(id, addr);
+	/// Reserves a connection id for an actor that's about to connect.
+	pub fn register(&self, user: Id<UserMarker>) -> Registration {
+		Registration {
+			map: self.map.clone(),
+			running: self.running.clone(),
+			user,
+			connection: self.next_id.fetch_add(1, Ordering::Relaxed),
+		}
+	}
+
+	/// Adds a connection, replacing the user's connection of the same name.
+	/// An actor that already stopped is left out, it couldn't take itself out again.
+	pub fn insert(&self, registration: &Registration, name: String, addr: Addr<ButtplugUser>) {
+		if !addr.connected() {
+			return;
+		}
+		let connection = Connection {
+			id: registration.connection,
+			name,
+			connected_at: Utc::now().naive_utc(),
+			addr,
+		};
+		let mut connections = self.map.entry(registration.user).or_default();
+		if let Some(old) = connections
+			.iter_mut()
+			.find(|old| old.name == connection.name)
+		{
+			old.addr.do_send(Disconnect);
+			*old = connection;
+		} else {
+			connections.push(connection);
+		}
+	}
+
+	/// Every user with at least one connection.
+	pub fn users(&self) -> Vec<Id<UserMarker>> {
+		self.map.iter().map(|entry| *entry.key()).collect()
+	}
+
+	pub fn get(&self, id: Id<UserMarker>) -> Option<UserConnections> {
+		self.map
+			.get(&id)
+			.map(|connections| UserConnections(connections.value().clone()))
+	}
+
+	pub fn list(&self, id: Id<UserMarker>) -> Vec<ConnectionInfo> {
+		self.map
+			.get(&id)
+			.map(|connections| connections.iter().map(ConnectionInfo::from).collect())
+			.unwrap_or_default()
+	}
+
+	/// Asks every connection of the user what it's up to.
+	pub async fn status(&self, id: Id<UserMarker>) -> Vec<ConnectionStatus> {
+		let connections = self
+			.map
+			.get(&id)
+			.map(|connections| connections.value().clone())
+			.unwrap_or_default();
+		join_all(connections.iter().map(|connection| async move {
+			let status = timeout(QUERY_TIMEOUT, connection.addr.send(GetStatus))
+				.await
+				.ok()
+				.and_then(Result::ok);
+			ConnectionStatus {
+				info: connection.into(),
+				responsive: status.is_some(),
+				status,
+			}
+		}))
+		.await
+	}
+
+	/// Removes one connection of the user.
+	pub fn remove(&self, id: Id<UserMarker>, name: &str) -> Option<Connection> {
+		let removed = {
+			let mut connections = self.map.get_mut(&id)?;
+			let index = connections
+				.iter()
+				.position(|connection| connection.name == name)?;
+			connections.remove(index)
+		};
+		self.map
+			.remove_if(&id, |_, connections| connections.is_empty());
+		Some(removed)
+	}
+
+	/// Removes every connection of the user.
+	pub fn remove_all(&self, id: Id<UserMarker>) -> Vec<Connection> {
+		self.map
+			.remove(&id)
+			.map(|(_, connections)| connections)
+			.unwrap_or_default()
 	}
 
-	pub fn get(&self, id: Id<UserMarker>) -> Option<Addr<ButtplugUser>> {
-		self.map.get(&id).map(|v| v.value().clone())
+	pub async fn stats(&self) -> ConnectionStats {
+		let users = self
+			.map
+			.iter()
+			.map(|entry| entry.value().clone())
+			.collect::<Vec<_>>();
+		let active = join_all(users.iter().map(|connections| async move {
+			let powers = join_all(
+				connections
+					.iter()
+					.map(|connection| timeout(QUERY_TIMEOUT, connection.addr.send(GetPower))),
+			)
+			.await;
+			powers.into_iter().any(|res| matches!(res, Ok(Ok(Some(_)))))
+		}))
+		.await
+		.into_iter()
+		.filter(|active| *active)
+		.count();
+		ConnectionStats {
+			connected: users.len(),
+			active,
+		}
 	}
 }
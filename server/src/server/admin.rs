@@ -0,0 +1,229 @@
+use actix_web::{
+	delete,
+	dev::HttpServiceFactory,
+	get, post, put,
+	web::{self, Data},
+	HttpResponse,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use twilight_model::id::{marker::UserMarker, Id};
+
+use crate::manager::{
+	admin::{AuditAction, AuditEntry, AuditFilter, AuditOutcome, Ban, ConnectedUser},
+	shards::ShardStatus,
+	stats::GuildSettings,
+	Manager,
+};
+
+use super::{
+	error::{Error, Result},
+	session::AdminUser,
+};
+
+/// Writes the request to the audit log with how it went, passing its result on.
+/// The action was taken already, failing to log it is only reported.
+async fn audited<T>(
+	manager: &Manager,
+	admin: &AdminUser,
+	action: AuditAction,
+	target: Option<Id<UserMarker>>,
+	detail: Option<String>,
+	result: Result<T>,
+) -> Result<T> {
+	let outcome = match &result {
+		Ok(_) => AuditOutcome::Done,
+		Err(Error::NotFound) => AuditOutcome::NotFound,
+		Err(_) => AuditOutcome::Failed,
+	};
+	if let Err(e) = manager
+		.audit(admin.id, action, target, detail, outcome)
+		.await
+	{
+		error!("Failed to audit {} by {}: {}", action.as_str(), admin.id, e);
+	}
+	result
+}
+
+#[get("/connections")]
+async fn get_connections(
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<web::Json<Vec<ConnectedUser>>> {
+	let result = Ok(web::Json(manager.connected_users().await));
+	audited(
+		&manager,
+		&admin,
+		AuditAction::ListConnections,
+		None,
+		None,
+		result,
+	)
+	.await
+}
+
+#[post("/users/{id}/stop")]
+async fn stop_user(
+	id: web::Path<Id<UserMarker>>,
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	let id = id.into_inner();
+	let result = if manager.force_stop(id) {
+		Ok(HttpResponse::NoContent().finish())
+	} else {
+		Err(Error::NotFound)
+	};
+	audited(
+		&manager,
+		&admin,
+		AuditAction::StopUser,
+		Some(id),
+		None,
+		result,
+	)
+	.await
+}
+
+#[post("/users/{id}/disconnect")]
+async fn disconnect_user(
+	id: web::Path<Id<UserMarker>>,
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	let id = id.into_inner();
+	let result = if manager.disconnect_all(id) {
+		Ok(HttpResponse::NoContent().finish())
+	} else {
+		Err(Error::NotFound)
+	};
+	audited(
+		&manager,
+		&admin,
+		AuditAction::DisconnectUser,
+		Some(id),
+		None,
+		result,
+	)
+	.await
+}
+
+#[get("/guilds")]
+async fn get_guilds(
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<web::Json<Vec<GuildSettings>>> {
+	let result = manager
+		.guild_settings()
+		.await
+		.map(web::Json)
+		.map_err(Error::from);
+	audited(
+		&manager,
+		&admin,
+		AuditAction::ListGuilds,
+		None,
+		None,
+		result,
+	)
+	.await
+}
+
+#[get("/shards")]
+async fn get_shards(
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<web::Json<Vec<ShardStatus>>> {
+	let result = Ok(web::Json(manager.shards.all()));
+	audited(
+		&manager,
+		&admin,
+		AuditAction::ListShards,
+		None,
+		None,
+		result,
+	)
+	.await
+}
+
+#[get("/bans")]
+async fn get_bans(admin: AdminUser, manager: Data<Manager>) -> Result<web::Json<Vec<Ban>>> {
+	let result = manager.bans().await.map(web::Json).map_err(Error::from);
+	audited(&manager, &admin, AuditAction::ListBans, None, None, result).await
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+	reason: Option<String>,
+}
+
+#[put("/bans/{id}")]
+async fn ban_user(
+	id: web::Path<Id<UserMarker>>,
+	web::Json(BanRequest { reason }): web::Json<BanRequest>,
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	let id = id.into_inner();
+	let result = manager
+		.ban(id, admin.id, reason.clone())
+		.await
+		.map(|()| HttpResponse::NoContent().finish())
+		.map_err(Error::from);
+	audited(&manager, &admin, AuditAction::Ban, Some(id), reason, result).await
+}
+
+#[delete("/bans/{id}")]
+async fn unban_user(
+	id: web::Path<Id<UserMarker>>,
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	let id = id.into_inner();
+	let result = match manager.unban(id).await {
+		Ok(true) => Ok(HttpResponse::NoContent().finish()),
+		Ok(false) => Err(Error::NotFound),
+		Err(e) => Err(e.into()),
+	};
+	audited(&manager, &admin, AuditAction::Unban, Some(id), None, result).await
+}
+
+#[derive(Serialize)]
+struct AuditLog {
+	entries: Vec<AuditEntry>,
+	/// Pass as `before` to get the next page, absent on the last page.
+	next: Option<i64>,
+}
+
+#[get("/audit")]
+async fn get_audit(
+	web::Query(filter): web::Query<AuditFilter>,
+	admin: AdminUser,
+	manager: Data<Manager>,
+) -> Result<web::Json<AuditLog>> {
+	let result = manager
+		.audit_log(&filter)
+		.await
+		.map_err(Error::from)
+		.map(|entries| {
+			let next = match entries.last() {
+				Some(last) if entries.len() as i64 == filter.limit() => Some(last.id),
+				_ => None,
+			};
+			web::Json(AuditLog { entries, next })
+		});
+	audited(&manager, &admin, AuditAction::ViewAudit, None, None, result).await
+}
+
+pub fn endpoints() -> impl HttpServiceFactory {
+	web::scope("/admin")
+		.service(get_connections)
+		.service(stop_user)
+		.service(disconnect_user)
+		.service(get_guilds)
+		.service(get_shards)
+		.service(get_bans)
+		.service(ban_user)
+		.service(unban_user)
+		.service(get_audit)
+}
@@ -5,6 +5,8 @@ use thiserror::Error;
 
 use log::error;
 
+use crate::manager::{AuthError, ManagerError};
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug, Error)]
@@ -15,12 +17,40 @@ pub enum Error {
 	SqlxError(#[from] sqlx::Error),
 	#[error("Invalid auth code passed")]
 	BadCode,
+	#[error("OAuth state doesn't match the one login started with")]
+	BadState,
+	#[error("Not logged in")]
+	Unauthorized,
+	#[error("Not found")]
+	NotFound,
+	#[error("Not an admin")]
+	Forbidden,
+	#[error("Banned from the service")]
+	Banned,
+	#[error("Connection names must be 1 to 32 characters long")]
+	BadConnectionName,
+	#[error("Discord auth error: {0}")]
+	Auth(AuthError),
+	#[error("Discord API error: {0}")]
+	Discord(twilight_http::Error),
 	#[error("Session get error: {0}")]
 	SessionGetError(#[from] actix_session::SessionGetError),
 	#[error("Session insert error: {0}")]
 	SessionInsertError(#[from] actix_session::SessionInsertError),
 }
 
+impl From<ManagerError> for Error {
+	fn from(e: ManagerError) -> Self {
+		match e {
+			ManagerError::Database(e) => Error::SqlxError(e),
+			ManagerError::InvalidCode => Error::BadCode,
+			ManagerError::Auth(e) => Error::Auth(e),
+			ManagerError::Banned => Error::Banned,
+			ManagerError::Discord(e) => Error::Discord(e),
+		}
+	}
+}
+
 impl ResponseError for Error {
 	fn error_response(&self) -> actix_web::HttpResponse {
 		match self {
@@ -32,6 +62,27 @@ impl ResponseError for Error {
 				HttpResponse::InternalServerError().finish()
 			}
 			Error::BadCode => HttpResponse::BadRequest().body("Invalid auth code passed"),
+			Error::BadState => HttpResponse::BadRequest()
+				.body("Login state mismatch, please start logging in again"),
+			Error::Unauthorized => HttpResponse::Unauthorized().finish(),
+			Error::NotFound => HttpResponse::NotFound().finish(),
+			Error::Forbidden => HttpResponse::Forbidden().finish(),
+			Error::Banned => HttpResponse::Forbidden().body("You're banned from this service"),
+			Error::BadConnectionName => {
+				HttpResponse::BadRequest().body("Connection names must be 1 to 32 characters long")
+			}
+			Error::Auth(AuthError::Unreachable(_)) => {
+				error!("Discord unreachable: {:?}", self);
+				HttpResponse::ServiceUnavailable().finish()
+			}
+			Error::Auth(_) => {
+				error!("Discord auth failed: {:?}", self);
+				HttpResponse::BadGateway().finish()
+			}
+			Error::Discord(_) => {
+				error!("Discord request failed: {:?}", self);
+				HttpResponse::BadGateway().finish()
+			}
 		}
 	}
 }
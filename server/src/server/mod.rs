@@ -1,28 +1,45 @@
+mod admin;
 pub mod error;
 pub mod session;
+pub mod store;
 
-use std::{env, str::FromStr, sync::Arc};
+use std::{
+	fs::File,
+	io::BufReader,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
+use actix_cors::Cors;
 use actix_session::{Session, SessionMiddleware};
 use error::{Error, Result};
+use session::AuthedUser;
 
 use actix_web::{
-	dev::HttpServiceFactory,
+	delete,
+	dev::{HttpServiceFactory, Service},
 	get,
+	http::header,
 	middleware::Logger,
 	post,
 	web::{self, Data},
 	App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use anyhow::Error as AnyError;
+use anyhow::{anyhow, Error as AnyError};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use twilight_model::id::{marker::GuildMarker, Id};
 
 use actix_buttplug::ButtplugContext;
-use twilight_model::id::{marker::UserMarker, Id};
 
 use crate::{
-	manager::{Manager, User},
+	config::Config,
+	manager::{
+		events::{Event, EventFilter},
+		stats::{Leaderboard, UserStats},
+		users::{ConnectionInfo, ConnectionStatus, DEFAULT_CONNECTION},
+		Manager, User,
+	},
 	user::ButtplugUser,
 };
 
@@ -31,19 +48,26 @@ async fn index() -> impl Responder {
 	"Hello, there!"
 }
 
+#[derive(Deserialize)]
+struct ConnectOptions {
+	/// Tells the user's connections apart, connecting again under the same name replaces the old one.
+	name: Option<String>,
+}
+
 #[get("/connect")]
 async fn connect(
 	req: HttpRequest,
 	stream: web::Payload,
-	ses: session::UserSession,
+	web::Query(ConnectOptions { name }): web::Query<ConnectOptions>,
+	AuthedUser { id, .. }: AuthedUser,
 	manager: Data<Manager>,
 ) -> Result<HttpResponse> {
-	let id = ses.get_id()?;
-	let id = match id {
-		Some(id) => Id::from_str(&id).expect("User ID should be valid"),
-		None => return Ok(HttpResponse::Unauthorized().finish()),
-	};
-	let actor = ButtplugUser::new();
+	let name = name.unwrap_or_else(|| DEFAULT_CONNECTION.to_owned());
+	if name.is_empty() || name.chars().count() > 32 {
+		return Err(Error::BadConnectionName);
+	}
+	let registration = manager.register(id);
+	let actor = ButtplugUser::new(id, manager.events.sender(), registration.clone());
 	let res = ButtplugContext::start_with_actix_ws_transport(
 		actor,
 		"Euphoria",
@@ -51,8 +75,8 @@ async fn connect(
 		stream,
 		move |addr| async move {
 			if let Ok(addr) = addr {
-				info!("Connected!");
-				manager.insert(id, addr);
+				info!("Connected {} of {}", name, id);
+				manager.insert(&registration, name, addr);
 			} else {
 				warn!("Failed to connect!");
 			}
@@ -62,28 +86,141 @@ async fn connect(
 	Ok(res)
 }
 
+#[get("/login/start")]
+async fn login_start(session: Session, manager: Data<Manager>) -> Result<HttpResponse> {
+	let start = manager.start_login();
+	session.insert("oauth_state", start.state)?;
+	match start.code_verifier {
+		Some(verifier) => session.insert("pkce_verifier", verifier)?,
+		None => {
+			session.remove("pkce_verifier");
+		}
+	}
+	Ok(HttpResponse::Found()
+		.insert_header((header::LOCATION, start.url))
+		.finish())
+}
+
 #[derive(Deserialize)]
 struct Code {
 	code: String,
+	state: String,
 }
 
 #[post("/login")]
 async fn login(
-	web::Query(Code { code }): web::Query<Code>,
-	session: Session,
+	web::Query(Code { code, state }): web::Query<Code>,
+	ses: session::UserSession,
 	manager: Data<Manager>,
 ) -> Result<HttpResponse> {
-	let user = manager.login(&code).await?;
-	if let Some(user) = user {
-		session.insert("user", user.id.clone())?;
+	let session = &ses.0;
+	let expected = session.get::<String>("oauth_state")?;
+	let verifier = session.get::<String>("pkce_verifier")?;
+	// Each state is only good for one attempt
+	session.remove("oauth_state");
+	session.remove("pkce_verifier");
+	if expected.as_deref() != Some(state.as_str()) {
+		warn!("Login attempted with a mismatched OAuth state");
+		return Err(Error::BadState);
 	}
+	let user = manager.login(&code, verifier.as_deref()).await?;
+	let epoch = manager.session_epoch(&user.id).await?.unwrap_or(0);
+	ses.login(user.id, epoch)?;
+	Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct LogoutOptions {
+	/// Also revoke the Discord token.
+	#[serde(default)]
+	revoke: bool,
+}
+
+#[post("/logout")]
+async fn logout(
+	web::Query(LogoutOptions { revoke }): web::Query<LogoutOptions>,
+	authed: AuthedUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	// Logged out here even if forgetting the tokens fails
+	authed.session.purge();
+	manager.logout(&authed.user.id, revoke).await?;
 	Ok(HttpResponse::Ok().finish())
 }
 
+/// Only this session is purged from the store, the others are rejected by their epoch from now on.
+#[post("/logout/all")]
+async fn logout_all(
+	web::Query(LogoutOptions { revoke }): web::Query<LogoutOptions>,
+	authed: AuthedUser,
+	manager: Data<Manager>,
+) -> Result<HttpResponse> {
+	manager.logout_all(&authed.user.id, revoke).await?;
+	authed.session.purge();
+	Ok(HttpResponse::Ok().finish())
+}
+
+/// Whether the toys are running, waiting for a trigger, or not there at all.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ToyState {
+	Running,
+	Armed,
+	/// Connected, but without any devices.
+	NoDevices,
+	/// Not connected.
+	Stopped,
+}
+
+#[derive(Serialize)]
+struct Me {
+	#[serde(flatten)]
+	user: User,
+	/// Whether any buttplug connection is live.
+	connected: bool,
+	/// Devices across every connection.
+	devices: usize,
+	/// Highest power across every connection.
+	power: Option<f64>,
+	state: ToyState,
+	connections: Vec<ConnectionStatus>,
+	/// The Discord token is gone, the user has to log in again for anything needing it.
+	needs_login: bool,
+}
+
+impl Me {
+	fn new(user: User, connections: Vec<ConnectionStatus>, needs_login: bool) -> Self {
+		let statuses = connections
+			.iter()
+			.filter_map(|connection| connection.status.as_ref());
+		let devices = statuses.clone().map(|status| status.devices.len()).sum();
+		let power = statuses
+			.filter_map(|status| status.power)
+			.fold(None, |max: Option<f64>, power| {
+				Some(max.map_or(power, |max| max.max(power)))
+			});
+		let state = match (power, devices) {
+			(Some(_), _) => ToyState::Running,
+			(None, 0) if connections.is_empty() => ToyState::Stopped,
+			(None, 0) => ToyState::NoDevices,
+			(None, _) => ToyState::Armed,
+		};
+		Self {
+			user,
+			connected: !connections.is_empty(),
+			devices,
+			power,
+			state,
+			connections,
+			needs_login,
+		}
+	}
+}
+
 #[derive(Serialize)]
 #[serde(tag = "status", content = "user")]
 enum UserLogin {
-	LoggedIn(User),
+	LoggedIn(Me),
 	LoggedOut,
 }
 
@@ -92,34 +229,230 @@ async fn get_user_data(
 	manager: Data<Manager>,
 	ses: session::UserSession,
 ) -> Result<web::Json<UserLogin>> {
-	match ses.get_user(manager.as_ref()).await? {
-		Some(user) => Ok(web::Json(UserLogin::LoggedIn(user))),
-		None => Ok(web::Json(UserLogin::LoggedOut)),
+	let user = match ses.get_user(manager.as_ref()).await? {
+		Some(user) => user,
+		None => return Ok(web::Json(UserLogin::LoggedOut)),
+	};
+	let connections = match user.id.parse() {
+		Ok(id) => manager.connection_status(id).await,
+		Err(_) => Vec::new(),
+	};
+	let needs_login = manager.needs_login(&user.id).await?;
+	Ok(web::Json(UserLogin::LoggedIn(Me::new(
+		user,
+		connections,
+		needs_login,
+	))))
+}
+
+#[get("/me/throttled")]
+async fn get_throttled(
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<HttpResponse> {
+	Ok(HttpResponse::Ok().json(manager.throttled(id)))
+}
+
+#[get("/me/connections")]
+async fn get_connections(
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> web::Json<Vec<ConnectionInfo>> {
+	web::Json(manager.connections(id))
+}
+
+#[delete("/me/connections/{name}")]
+async fn delete_connection(
+	name: web::Path<String>,
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<HttpResponse> {
+	if manager.disconnect(id, &name) {
+		Ok(HttpResponse::NoContent().finish())
+	} else {
+		Err(Error::NotFound)
+	}
+}
+
+#[derive(Serialize)]
+struct History {
+	events: Vec<Event>,
+	/// Pass as `before` to get the next page, absent on the last page.
+	next: Option<i64>,
+}
+
+#[get("/me/history")]
+async fn get_history(
+	web::Query(filter): web::Query<EventFilter>,
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<web::Json<History>> {
+	let events = manager.history(id, &filter).await?;
+	let next = match events.last() {
+		Some(last) if events.len() as i64 == filter.limit() => Some(last.id),
+		_ => None,
+	};
+	Ok(web::Json(History { events, next }))
+}
+
+#[get("/me/stats")]
+async fn get_stats(
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<web::Json<UserStats>> {
+	Ok(web::Json(manager.stats(id).await?))
+}
+
+/// Everything stored about the user, as a download.
+#[get("/me/export")]
+async fn get_export(
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<HttpResponse> {
+	let export = manager.export(id).await?.ok_or(Error::Unauthorized)?;
+	Ok(HttpResponse::Ok()
+		.insert_header((
+			header::CONTENT_DISPOSITION,
+			"attachment; filename=\"euphoria-export.json\"",
+		))
+		.json(export))
+}
+
+/// Only this session is purged from the store. The user's other sessions, e.g. on other devices,
+/// are left there since the store can't be searched by user, but the bumped epoch rejects them
+/// from now on and they're purged the next time they're used or expire on their own.
+#[delete("/me")]
+async fn delete_user(authed: AuthedUser, manager: Data<Manager>) -> Result<HttpResponse> {
+	manager.delete_account(authed.id).await?;
+	authed.session.purge();
+	Ok(HttpResponse::NoContent().finish())
+}
+
+/// 404s unless the guild opted in to leaderboards and the user is a member of it.
+#[get("/guilds/{id}/leaderboard")]
+async fn get_leaderboard(
+	guild_id: web::Path<Id<GuildMarker>>,
+	manager: Data<Manager>,
+	AuthedUser { id, .. }: AuthedUser,
+) -> Result<web::Json<Leaderboard>> {
+	let guild_id = guild_id.into_inner();
+	if !manager.is_member(guild_id, id).await? {
+		return Err(Error::NotFound);
+	}
+	match manager.leaderboard(guild_id).await? {
+		Some(leaderboard) => Ok(web::Json(leaderboard)),
+		None => Err(Error::NotFound),
 	}
 }
 
 fn endpoints() -> impl HttpServiceFactory {
 	web::scope("/api")
 		.service(index)
+		.service(login_start)
 		.service(login)
+		.service(logout)
+		.service(logout_all)
 		.service(connect)
 		.service(get_user_data)
+		.service(get_throttled)
+		.service(get_connections)
+		.service(delete_connection)
+		.service(get_history)
+		.service(get_stats)
+		.service(get_export)
+		.service(delete_user)
+		.service(get_leaderboard)
+		.service(admin::endpoints())
 }
 
-pub async fn run_http_server(manager: Arc<Manager>) -> Result<(), AnyError> {
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+	pub address: String,
+	pub port: u16,
+	/// Certificate chain and private key, both PEM encoded. Serves HTTPS when both are set.
+	pub tls_cert: Option<PathBuf>,
+	pub tls_key: Option<PathBuf>,
+	/// Origins allowed to make credentialed cross-origin requests, i.e. the web app.
+	pub cors_origins: Vec<String>,
+}
+
+impl Default for HttpSettings {
+	fn default() -> Self {
+		Self {
+			address: "127.0.0.1".into(),
+			port: 4000,
+			tls_cert: None,
+			tls_key: None,
+			cors_origins: Vec::new(),
+		}
+	}
+}
+
+fn load_tls(cert: &Path, key: &Path) -> Result<rustls::ServerConfig, AnyError> {
+	let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+		.into_iter()
+		.map(rustls::Certificate)
+		.collect();
+	let mut key_reader = BufReader::new(File::open(key)?);
+	let key = rustls_pemfile::read_all(&mut key_reader)?
+		.into_iter()
+		.find_map(|item| match item {
+			rustls_pemfile::Item::PKCS8Key(key)
+			| rustls_pemfile::Item::RSAKey(key)
+			| rustls_pemfile::Item::ECKey(key) => Some(key),
+			_ => None,
+		})
+		.ok_or_else(|| anyhow!("No private key found in {}", key.display()))?;
+	let config = rustls::ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_single_cert(certs, rustls::PrivateKey(key))?;
+	Ok(config)
+}
+
+fn cors(origins: &[String]) -> Cors {
+	origins
+		.iter()
+		.fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+		.allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+		.allowed_header(header::CONTENT_TYPE)
+		.supports_credentials()
+		.max_age(3600)
+}
+
+pub async fn run_http_server(manager: Arc<Manager>, config: Arc<Config>) -> Result<(), AnyError> {
 	info!("Configuring and starting web server");
-	let (store, key) = session::setup_sessions().await;
-	HttpServer::new(move || {
+	let settings = &config.http;
+	let (store, keys) = session::setup_sessions(&config).await?;
+	let cors_origins = settings.cors_origins.clone();
+	let server = HttpServer::new(move || {
+		let keys = keys.clone();
 		App::new()
 			.app_data(Data::from(manager.clone()))
-			.wrap(actix_cors::Cors::permissive())
+			.wrap(cors(&cors_origins))
 			.wrap(Logger::new("%r %U %s"))
-			.wrap(SessionMiddleware::new(store.clone(), key.clone()))
+			.wrap_fn(|req, srv| {
+				session::renew_rotated(&req);
+				srv.call(req)
+			})
+			.wrap(SessionMiddleware::new(store.clone(), keys.primary.clone()))
+			.wrap_fn(move |mut req, srv| {
+				keys.rotate_request(&mut req);
+				srv.call(req)
+			})
 			.service(endpoints())
-	})
-	.bind(("127.0.0.1", 4000))
-	.expect("Failed to bind to port 4000")
-	.run()
-	.await
-	.map_err(AnyError::from)
+	});
+	let address = (settings.address.as_str(), settings.port);
+	let server = match (&settings.tls_cert, &settings.tls_key) {
+		(Some(cert), Some(key)) => {
+			info!("Serving HTTPS on {}:{}", settings.address, settings.port);
+			server.bind_rustls(address, load_tls(cert, key)?)?
+		}
+		_ => {
+			info!("Serving HTTP on {}:{}", settings.address, settings.port);
+			server.bind(address)?
+		}
+	};
+	server.run().await.map_err(AnyError::from)
 }
@@ -1,43 +1,204 @@
-use std::convert::Infallible;
-use std::env;
+use std::convert::{Infallible, TryFrom};
+use std::{fs, str::FromStr};
 
 use actix::fut::{ready, Ready};
 use actix_session::storage::RedisSessionStore;
 use actix_session::{Session, SessionExt};
-use actix_web::cookie::Key;
-use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use log::{info, warn};
 
-use crate::manager::{Manager, User};
+use twilight_model::id::{marker::UserMarker, Id};
 
-use super::error::Result;
+use crate::{
+	config::{Config, SessionConfig},
+	manager::{Manager, User},
+};
 
-pub async fn setup_sessions() -> (RedisSessionStore, Key) {
-	let redis_uri = env::var("REDIS_URI").expect("REDIS_URI not set");
-	let store = RedisSessionStore::builder(redis_uri)
-		.cache_keygen(|key| {
-			let mut key = key.to_string();
-			key.push_str(":session");
-			key
-		})
-		.build()
-		.await
-		.expect("Couldn't connect to Redis");
-	let key = Key::generate();
-	(store, key)
+use super::{
+	error::{Error, Result},
+	store::{Store, StoreKind},
+};
+
+/// Name of the cookie actix-session keeps the session key in.
+const SESSION_COOKIE: &str = "id";
+
+/// Marks a request whose session cookie was made with a previous key.
+struct Rotated;
+
+/// Keys session cookies are encrypted with. Cookies made with a previous key are still accepted,
+/// and are re-encrypted with the primary key.
+#[derive(Clone)]
+pub struct SessionKeys {
+	pub primary: Key,
+	pub previous: Vec<Key>,
+}
+
+/// Decodes a base64 encoded key of at least 64 bytes.
+pub fn decode_key(encoded: &str) -> std::result::Result<Key, String> {
+	let bytes = base64::decode(encoded.trim()).map_err(|e| format!("not valid base64: {}", e))?;
+	Key::try_from(bytes.as_slice()).map_err(|_| "must be at least 64 bytes long".to_owned())
+}
+
+impl SessionKeys {
+	/// Loads the configured keys.
+	/// Outside of production a missing key is generated, logging everyone out on restart.
+	pub fn load(config: &SessionConfig, production: bool) -> anyhow::Result<Self> {
+		let primary = match (&config.key, &config.key_file) {
+			(Some(key), _) => decode_key(key).map_err(anyhow::Error::msg)?,
+			(None, Some(path)) => {
+				decode_key(&fs::read_to_string(path)?).map_err(anyhow::Error::msg)?
+			}
+			(None, None) => {
+				if production {
+					anyhow::bail!("A session key must be configured in production");
+				}
+				warn!("No session key configured, sessions won't survive a restart");
+				Key::generate()
+			}
+		};
+		let previous = config
+			.previous_keys
+			.iter()
+			.map(|key| decode_key(key).map_err(anyhow::Error::msg))
+			.collect::<anyhow::Result<_>>()?;
+		Ok(Self { primary, previous })
+	}
+
+	/// Swaps a session cookie made with a previous key for one made with the primary key,
+	/// before the session middleware gets to see it. [`renew_rotated`] then has the client
+	/// store the new cookie.
+	pub fn rotate_request(&self, req: &mut ServiceRequest) {
+		if self.previous.is_empty() {
+			return;
+		}
+		let cookies = match req
+			.headers()
+			.get(header::COOKIE)
+			.and_then(|value| value.to_str().ok())
+		{
+			Some(cookies) => cookies.to_owned(),
+			None => return,
+		};
+		let mut rotated = false;
+		let cookies = cookies
+			.split(';')
+			.map(|pair| {
+				let pair = pair.trim();
+				let value = match Cookie::parse(pair) {
+					Ok(cookie) if cookie.name() == SESSION_COOKIE => self.rotate(cookie.value()),
+					_ => None,
+				};
+				match value {
+					Some(value) => {
+						rotated = true;
+						format!("{}={}", SESSION_COOKIE, value)
+					}
+					None => pair.to_owned(),
+				}
+			})
+			.collect::<Vec<_>>()
+			.join("; ");
+		if rotated {
+			if let Ok(value) = HeaderValue::from_str(&cookies) {
+				req.headers_mut().insert(header::COOKIE, value);
+				req.extensions_mut().insert(Rotated);
+			}
+		}
+	}
+
+	fn rotate(&self, value: &str) -> Option<String> {
+		let mut jar = CookieJar::new();
+		jar.add_original(Cookie::new(SESSION_COOKIE, value.to_owned()));
+		if jar.private(&self.primary).get(SESSION_COOKIE).is_some() {
+			return None;
+		}
+		let plain = self
+			.previous
+			.iter()
+			.find_map(|key| jar.private(key).get(SESSION_COOKIE))?;
+		let mut rotated = CookieJar::new();
+		rotated
+			.private_mut(&self.primary)
+			.add(Cookie::new(SESSION_COOKIE, plain.value().to_owned()));
+		rotated
+			.get(SESSION_COOKIE)
+			.map(|cookie| cookie.value().to_owned())
+	}
+}
+
+/// Renews sessions whose cookie was rotated, so the response sets a cookie made with the primary key.
+/// Has to run inside the session middleware.
+pub fn renew_rotated(req: &ServiceRequest) {
+	let rotated = req.extensions().contains::<Rotated>();
+	if rotated {
+		req.get_session().renew();
+	}
+}
+
+pub async fn setup_sessions(config: &Config) -> anyhow::Result<(Store, SessionKeys)> {
+	let session = &config.session;
+	let kind = session.store();
+	let store = match kind {
+		StoreKind::Redis => {
+			let redis_uri = session
+				.redis_uri
+				.clone()
+				.ok_or_else(|| anyhow::anyhow!("No Redis URI configured"))?;
+			let store = RedisSessionStore::builder(redis_uri)
+				.cache_keygen(|key| {
+					let mut key = key.to_string();
+					key.push_str(":session");
+					key
+				})
+				.build()
+				.await?;
+			Store::Redis(store)
+		}
+		StoreKind::Cookie => Store::Cookie,
+		StoreKind::Memory => {
+			if session.store.is_none() {
+				warn!("No Redis URI configured, keeping sessions in memory");
+			}
+			Store::Memory(Default::default())
+		}
+	};
+	info!("Keeping sessions in {:?} store", kind);
+	Ok((store, SessionKeys::load(session, config.production)?))
 }
 
 pub struct UserSession(pub Session);
 
 impl UserSession {
-	pub fn get_id(&self) -> Result<Option<String>> {
-		Ok(self.0.get::<String>("user")?)
+	pub fn login(&self, id: String, epoch: i32) -> Result<()> {
+		self.0.renew();
+		self.0.insert("user", id)?;
+		self.0.insert("epoch", epoch)?;
+		Ok(())
 	}
 
-	pub async fn get_user(&self, manager: &Manager) -> Result<Option<User>> {
+	/// The logged in user's id, if the session hasn't been revoked since.
+	pub async fn get_id(&self, manager: &Manager) -> Result<Option<String>> {
 		let id = match self.0.get::<String>("user")? {
 			Some(id) => id,
 			None => return Ok(None),
 		};
+		let epoch = self.0.get::<i32>("epoch")?.unwrap_or(0);
+		if manager.session_epoch(&id).await? != Some(epoch) {
+			self.0.purge();
+			return Ok(None);
+		}
+		Ok(Some(id))
+	}
+
+	pub async fn get_user(&self, manager: &Manager) -> Result<Option<User>> {
+		let id = match self.get_id(manager).await? {
+			Some(id) => id,
+			None => return Ok(None),
+		};
 		let user = manager.get_user(&id).await?;
 		Ok(user)
 	}
@@ -51,3 +212,60 @@ impl FromRequest for UserSession {
 		ready(Ok(UserSession(session)))
 	}
 }
+
+/// A logged in user. Extracting it fails with 401 Unauthorized when the session has no user,
+/// has been revoked, or doesn't hold a valid user id.
+pub struct AuthedUser {
+	pub id: Id<UserMarker>,
+	pub user: User,
+	pub session: Session,
+}
+
+impl FromRequest for AuthedUser {
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+	fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+		let session = UserSession(req.get_session());
+		let manager = req.app_data::<Data<Manager>>().cloned();
+		Box::pin(async move {
+			let manager = manager.expect("Manager is registered as app data");
+			let user = session
+				.get_user(&manager)
+				.await?
+				.ok_or(Error::Unauthorized)?;
+			let id = Id::from_str(&user.id).map_err(|_| {
+				warn!("Session holds an invalid user id: {}", user.id);
+				Error::Unauthorized
+			})?;
+			Ok(AuthedUser {
+				id,
+				user,
+				session: session.0,
+			})
+		})
+	}
+}
+
+/// A logged in operator, listed in `admin.ids`.
+/// Extracting it fails with 401 Unauthorized when not logged in, and 403 Forbidden for everyone else.
+pub struct AdminUser {
+	pub id: Id<UserMarker>,
+}
+
+impl FromRequest for AdminUser {
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+	fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+		let authed = AuthedUser::from_request(req, payload);
+		let manager = req.app_data::<Data<Manager>>().cloned();
+		Box::pin(async move {
+			let manager = manager.expect("Manager is registered as app data");
+			let AuthedUser { id, .. } = authed.await?;
+			if !manager.is_admin(id) {
+				warn!("User {} tried to use the admin API", id);
+				return Err(Error::Forbidden);
+			}
+			Ok(AdminUser { id })
+		})
+	}
+}
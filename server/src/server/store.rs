@@ -0,0 +1,182 @@
+use std::{
+	collections::HashMap,
+	convert::TryFrom,
+	str::FromStr,
+	sync::Arc,
+	time::{Duration as StdDuration, Instant},
+};
+
+use actix_session::storage::{
+	CookieSessionStore, LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore,
+	UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+
+type SessionState = HashMap<String, String>;
+
+/// Where sessions are kept, picked with `session.store`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+	/// In Redis at `session.redis_uri`, shared between server instances and kept across restarts.
+	Redis,
+	/// Inside the session cookie itself, nothing is kept on the server.
+	Cookie,
+	/// In this process, for development and single node setups.
+	Memory,
+}
+
+impl FromStr for StoreKind {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"redis" => Ok(StoreKind::Redis),
+			"cookie" => Ok(StoreKind::Cookie),
+			"memory" => Ok(StoreKind::Memory),
+			other => Err(format!("Unknown session store: {}", other)),
+		}
+	}
+}
+
+#[derive(Clone)]
+pub enum Store {
+	Redis(RedisSessionStore),
+	Cookie,
+	Memory(MemorySessionStore),
+}
+
+#[async_trait(?Send)]
+impl SessionStore for Store {
+	async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+		match self {
+			Store::Redis(store) => store.load(session_key).await,
+			Store::Cookie => CookieSessionStore::default().load(session_key).await,
+			Store::Memory(store) => store.load(session_key).await,
+		}
+	}
+
+	async fn save(
+		&self,
+		session_state: SessionState,
+		ttl: &Duration,
+	) -> Result<SessionKey, SaveError> {
+		match self {
+			Store::Redis(store) => store.save(session_state, ttl).await,
+			Store::Cookie => CookieSessionStore::default().save(session_state, ttl).await,
+			Store::Memory(store) => store.save(session_state, ttl).await,
+		}
+	}
+
+	async fn update(
+		&self,
+		session_key: SessionKey,
+		session_state: SessionState,
+		ttl: &Duration,
+	) -> Result<SessionKey, UpdateError> {
+		match self {
+			Store::Redis(store) => store.update(session_key, session_state, ttl).await,
+			Store::Cookie => {
+				CookieSessionStore::default()
+					.update(session_key, session_state, ttl)
+					.await
+			}
+			Store::Memory(store) => store.update(session_key, session_state, ttl).await,
+		}
+	}
+
+	async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+		match self {
+			Store::Redis(store) => store.update_ttl(session_key, ttl).await,
+			Store::Cookie => {
+				CookieSessionStore::default()
+					.update_ttl(session_key, ttl)
+					.await
+			}
+			Store::Memory(store) => store.update_ttl(session_key, ttl).await,
+		}
+	}
+
+	async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+		match self {
+			Store::Redis(store) => store.delete(session_key).await,
+			Store::Cookie => CookieSessionStore::default().delete(session_key).await,
+			Store::Memory(store) => store.delete(session_key).await,
+		}
+	}
+}
+
+/// Keeps sessions in a map inside this process. They are lost on restart.
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+	sessions: Arc<DashMap<String, (SessionState, Instant)>>,
+}
+
+fn expiry(ttl: &Duration) -> Instant {
+	let ttl = StdDuration::try_from(*ttl).unwrap_or_default();
+	Instant::now() + ttl
+}
+
+impl MemorySessionStore {
+	fn prune(&self) {
+		let now = Instant::now();
+		self.sessions.retain(|_, (_, expires)| *expires > now);
+	}
+}
+
+#[async_trait(?Send)]
+impl SessionStore for MemorySessionStore {
+	async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+		Ok(self
+			.sessions
+			.get(session_key.as_ref())
+			.filter(|entry| entry.1 > Instant::now())
+			.map(|entry| entry.0.clone()))
+	}
+
+	async fn save(
+		&self,
+		session_state: SessionState,
+		ttl: &Duration,
+	) -> Result<SessionKey, SaveError> {
+		self.prune();
+		let key: String = rand::thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(64)
+			.map(char::from)
+			.collect();
+		self.sessions
+			.insert(key.clone(), (session_state, expiry(ttl)));
+		SessionKey::try_from(key).map_err(|e| SaveError::Other(anyhow!(e)))
+	}
+
+	async fn update(
+		&self,
+		session_key: SessionKey,
+		session_state: SessionState,
+		ttl: &Duration,
+	) -> Result<SessionKey, UpdateError> {
+		self.sessions.insert(
+			session_key.as_ref().to_owned(),
+			(session_state, expiry(ttl)),
+		);
+		Ok(session_key)
+	}
+
+	async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+		if let Some(mut entry) = self.sessions.get_mut(session_key.as_ref()) {
+			entry.1 = expiry(ttl);
+		}
+		Ok(())
+	}
+
+	async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+		self.sessions.remove(session_key.as_ref());
+		Ok(())
+	}
+}
@@ -3,15 +3,24 @@ use std::{collections::HashMap, sync::Arc};
 use actix::prelude::*;
 use actix_buttplug::ButtplugContext;
 use buttplug::client::{ButtplugClientDevice, ButtplugClientEvent, VibrateCommand};
+use chrono::{NaiveDateTime, Utc};
 use futures::{
 	future::{join_all, BoxFuture},
 	Future,
 };
 use log::{error, warn};
 use regex::Regex;
+use serde::Serialize;
 use tokio::time::{Duration, Instant};
+use twilight_model::id::{marker::UserMarker, Id};
 
-#[derive(Debug, Clone, Copy)]
+use crate::manager::{
+	events::{EventKind, EventSender, NewEvent},
+	users::Registration,
+};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "kind", content = "seconds", rename_all = "snake_case")]
 pub enum Decay {
 	/// Half life in seconds
 	HalfLife(f64),
@@ -23,10 +32,11 @@ impl Decay {
 	fn decay_power(self, current: f64, delta: f64) -> Option<f64> {
 		match self {
 			Decay::HalfLife(hl) => {
-				if current < 1e-8 {
+				let next = current * (2.0 as f64).powf(-delta / hl);
+				if next < 1e-8 {
 					None
 				} else {
-					Some(current * (2.0 as f64).powf(-delta / hl))
+					Some(next)
 				}
 			}
 			Decay::Linear(time) => {
@@ -39,6 +49,14 @@ impl Decay {
 			}
 		}
 	}
+
+	/// Seconds it takes `power` to decay until the toys stop.
+	fn time_to_stop(self, power: f64) -> f64 {
+		match self {
+			Decay::HalfLife(hl) => hl * (power / 1e-8).log2().max(0.0),
+			Decay::Linear(time) => power * time,
+		}
+	}
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -72,6 +90,26 @@ enum DeviceFrame {
 	// },
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSummary {
+	pub index: u32,
+	pub name: String,
+	/// Power the device was last set to, `None` while it's stopped.
+	pub power: Option<f64>,
+}
+
+impl DeviceFrame {
+	fn summary(&self) -> DeviceSummary {
+		match self {
+			DeviceFrame::Simple { device, power, .. } => DeviceSummary {
+				index: device.index(),
+				name: device.name().to_string(),
+				power: *power,
+			},
+		}
+	}
+}
+
 fn step_count_to_interval(count: u32) -> f64 {
 	1.0 / (count as f64)
 }
@@ -163,9 +201,21 @@ impl DeviceFrame {
 	}
 }
 
+/// How much a trigger changed the power, and what it ended up at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerChange {
+	pub delta: f64,
+	pub power: f64,
+}
+
 pub struct ButtplugUser {
+	id: Id<UserMarker>,
+	events: EventSender,
+	registration: Registration,
 	power: Option<f64>,
 	power_instant: Instant,
+	/// Settles the power once it has decayed all the way, so the stop is noticed on time.
+	stop_handle: Option<SpawnHandle>,
 	devices: HashMap<u32, DeviceFrame>,
 	decay: Decay,
 	regex: Regex,
@@ -189,6 +239,13 @@ impl Actor for ButtplugUser {
 			.into_iter()
 			.for_each(|device| self.add_device(ctx, device));
 	}
+
+	/// The connection closed, the devices went with it.
+	fn stopped(&mut self, _ctx: &mut Self::Context) {
+		self.settle();
+		self.record_stop("closed");
+		self.registration.release();
+	}
 }
 
 impl StreamHandler<ButtplugClientEvent> for ButtplugUser {
@@ -207,10 +264,14 @@ impl StreamHandler<ButtplugClientEvent> for ButtplugUser {
 }
 
 impl ButtplugUser {
-	pub fn new() -> Self {
+	pub fn new(id: Id<UserMarker>, events: EventSender, registration: Registration) -> Self {
 		Self {
+			id,
+			events,
+			registration,
 			power: None,
 			power_instant: Instant::now(),
+			stop_handle: None,
 			devices: HashMap::new(),
 			decay: Decay::Linear(2.0),
 			regex: Regex::new("(?i)(?:good (?:girl|kitt(?:y|en))|treat|reward|praise|slut|cum)")
@@ -243,9 +304,63 @@ impl ButtplugUser {
 		}
 	}
 
+	/// Power right now, taking into account how much it has decayed since it was last set.
+	fn current_power(&self) -> Option<f64> {
+		let delta = self.power_instant.elapsed().as_secs_f64();
+		self.power
+			.and_then(|power| self.decay.decay_power(power, delta))
+	}
+
+	/// Records the stop of toys that decayed all the way since the power was last set,
+	/// at the time they ran out rather than whenever we happen to notice.
+	fn settle(&mut self) {
+		let power = match self.power {
+			Some(power) if self.current_power().is_none() => power,
+			_ => return,
+		};
+		let stopped = self.power_instant + Duration::from_secs_f64(self.decay.time_to_stop(power));
+		let ago = chrono::Duration::from_std(Instant::now().saturating_duration_since(stopped))
+			.unwrap_or_else(|_| chrono::Duration::zero());
+		self.stopped_at(power, "decay", Utc::now().naive_utc() - ago);
+		self.power = None;
+	}
+
+	/// Records that the toys stopped at `at`, unless another connection of the user keeps theirs running.
+	/// The user's toys stopped when the last connection running them did.
+	fn stopped_at(&self, power: f64, reason: &str, at: NaiveDateTime) {
+		if let Some(at) = self.registration.stop(at) {
+			let change = PowerChange {
+				delta: -power,
+				power: 0.0,
+			};
+			self.events.record(
+				NewEvent::new(self.id, EventKind::Stop, change)
+					.detail(reason)
+					.at(at),
+			);
+		}
+	}
+
+	/// Settles the power when it will have decayed all the way.
+	fn schedule_stop(&mut self, ctx: &mut ButtplugContext<Self>) {
+		if let Some(handle) = self.stop_handle.take() {
+			ctx.cancel_future(handle);
+		}
+		if let Some(power) = self.power {
+			let stop_in = Duration::from_secs_f64(self.decay.time_to_stop(power) + 0.0001);
+			let handle = ctx.run_later(stop_in, |user, _ctx| {
+				user.stop_handle = None;
+				user.settle();
+			});
+			self.stop_handle = Some(handle);
+		}
+	}
+
 	fn set_power(&mut self, ctx: &mut ButtplugContext<Self>, power: f64) {
 		self.power = Some(power);
 		self.power_instant = Instant::now();
+		self.registration.start();
+		self.schedule_stop(ctx);
 		let decay = self.decay;
 		let futs = self
 			.devices
@@ -258,8 +373,17 @@ impl ButtplugUser {
 		ctx.spawn(fut.into_actor(self));
 	}
 
-	fn stop_devices(&mut self, ctx: &mut ButtplugContext<Self>) {
+	/// Records that the toys stopped, if they were running.
+	fn record_stop(&mut self, reason: &str) {
+		if let Some(power) = self.current_power() {
+			self.stopped_at(power, reason, Utc::now().naive_utc());
+		}
 		self.power = None;
+	}
+
+	/// Stops every device, recording the stop if they were running.
+	fn stop_devices(&mut self, ctx: &mut ButtplugContext<Self>, reason: &str) {
+		self.record_stop(reason);
 		let futs = self.devices.values_mut().map(|d| d.stop_device(ctx));
 		let fut = join_all(futs);
 		let fut = async {
@@ -269,39 +393,141 @@ impl ButtplugUser {
 	}
 }
 
-pub struct Flirt(pub String);
+/// Asks whether a message would count as a flirt, without acting on it.
+#[derive(Clone)]
+pub struct IsFlirt(pub String);
+
+impl Message for IsFlirt {
+	type Result = bool;
+}
+
+impl Handler<IsFlirt> for ButtplugUser {
+	type Result = bool;
+
+	fn handle(&mut self, msg: IsFlirt, _ctx: &mut Self::Context) -> Self::Result {
+		self.regex.is_match(&msg.0)
+	}
+}
+
+/// A flirty message, with the weight the throttle gave it.
+/// Answers with the matched phrase and the change in power, if it matched.
+#[derive(Clone)]
+pub struct Flirt(pub String, pub f64);
 
 impl Message for Flirt {
-	type Result = ();
+	type Result = Option<(String, PowerChange)>;
 }
 
 impl Handler<Flirt> for ButtplugUser {
-	type Result = ();
+	type Result = Option<(String, PowerChange)>;
 
 	fn handle(&mut self, msg: Flirt, ctx: &mut Self::Context) -> Self::Result {
-		if self.regex.is_match(&msg.0) {
-			let new_power = self.power.unwrap_or(0.0) + 0.3;
-			self.power = Some(new_power);
-			self.power_instant = Instant::now();
-			self.set_power(ctx, new_power);
-		}
+		self.settle();
+		let phrase = self.regex.find(&msg.0)?.as_str().to_owned();
+		let delta = 0.3 * msg.1;
+		let new_power = self.power.unwrap_or(0.0) + delta;
+		self.power = Some(new_power);
+		self.power_instant = Instant::now();
+		self.set_power(ctx, new_power);
+		Some((
+			phrase,
+			PowerChange {
+				delta,
+				power: new_power,
+			},
+		))
 	}
 }
 
-pub struct Reaction;
+/// A reaction, with the weight the throttle gave it.
+#[derive(Clone)]
+pub struct Reaction(pub f64);
 
 impl Message for Reaction {
-	type Result = ();
+	type Result = PowerChange;
 }
 
 impl Handler<Reaction> for ButtplugUser {
-	type Result = ();
+	type Result = MessageResult<Reaction>;
 
-	fn handle(&mut self, _msg: Reaction, ctx: &mut Self::Context) -> Self::Result {
-		let new_power = self.power.unwrap_or(0.0) + 0.3;
+	fn handle(&mut self, msg: Reaction, ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		let delta = 0.3 * msg.0;
+		let new_power = self.power.unwrap_or(0.0) + delta;
 		self.power = Some(new_power);
 		self.power_instant = Instant::now();
 		self.set_power(ctx, new_power);
+		MessageResult(PowerChange {
+			delta,
+			power: new_power,
+		})
+	}
+}
+
+/// A removed reaction, taking back the weight it was credited with.
+/// Answers with the change in power, if the toys were running.
+#[derive(Clone)]
+pub struct Unreact(pub f64);
+
+impl Message for Unreact {
+	type Result = Option<PowerChange>;
+}
+
+impl Handler<Unreact> for ButtplugUser {
+	type Result = Option<PowerChange>;
+
+	fn handle(&mut self, msg: Unreact, ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		let last_power = self.current_power()?;
+		let new_power = last_power - 0.3 * msg.0;
+		if new_power < 1e-8 {
+			self.stop_devices(ctx, "unreact");
+			Some(PowerChange {
+				delta: -last_power,
+				power: 0.0,
+			})
+		} else {
+			self.set_power(ctx, new_power);
+			Some(PowerChange {
+				delta: new_power - last_power,
+				power: new_power,
+			})
+		}
+	}
+}
+
+/// Stops the devices and closes the connection.
+#[derive(Clone)]
+pub struct Disconnect;
+
+impl Message for Disconnect {
+	type Result = ();
+}
+
+impl Handler<Disconnect> for ButtplugUser {
+	type Result = ();
+
+	fn handle(&mut self, _msg: Disconnect, ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		self.stop_devices(ctx, "disconnect");
+		ctx.stop();
+	}
+}
+
+/// Stops the devices, keeping the connection open.
+#[derive(Clone)]
+pub struct ForceStop;
+
+impl Message for ForceStop {
+	type Result = ();
+}
+
+impl Handler<ForceStop> for ButtplugUser {
+	type Result = ();
+
+	fn handle(&mut self, _msg: ForceStop, ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		self.stop_devices(ctx, "admin");
 	}
 }
 
@@ -314,14 +540,69 @@ impl Message for SetDecay {
 impl Handler<SetDecay> for ButtplugUser {
 	type Result = ();
 
-	fn handle(&mut self, msg: SetDecay, _ctx: &mut Self::Context) -> Self::Result {
+	fn handle(&mut self, msg: SetDecay, ctx: &mut Self::Context) -> Self::Result {
 		if let Some(last_power) = self.power {
 			let now = Instant::now();
 			let delta = now.duration_since(self.power_instant).as_secs_f64();
-			self.power_instant = now;
-			let new_power = self.decay.decay_power(last_power, delta);
-			self.power = new_power;
+			match self.decay.decay_power(last_power, delta) {
+				Some(new_power) => {
+					self.power = Some(new_power);
+					self.power_instant = now;
+				}
+				// Ran out under the old decay
+				None => self.settle(),
+			}
 		}
 		self.decay = msg.0;
+		self.schedule_stop(ctx);
+	}
+}
+
+#[derive(Clone)]
+pub struct GetPower;
+
+impl Message for GetPower {
+	type Result = Option<f64>;
+}
+
+impl Handler<GetPower> for ButtplugUser {
+	type Result = Option<f64>;
+
+	fn handle(&mut self, _msg: GetPower, _ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		self.current_power()
+	}
+}
+
+/// What a connection is up to right now.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+	pub devices: Vec<DeviceSummary>,
+	pub power: Option<f64>,
+	pub decay: Decay,
+}
+
+pub struct GetStatus;
+
+impl Message for GetStatus {
+	type Result = Status;
+}
+
+impl Handler<GetStatus> for ButtplugUser {
+	type Result = MessageResult<GetStatus>;
+
+	fn handle(&mut self, _msg: GetStatus, _ctx: &mut Self::Context) -> Self::Result {
+		self.settle();
+		let mut devices = self
+			.devices
+			.values()
+			.map(DeviceFrame::summary)
+			.collect::<Vec<_>>();
+		devices.sort_by_key(|device| device.index);
+		MessageResult(Status {
+			devices,
+			power: self.current_power(),
+			decay: self.decay,
+		})
 	}
 }
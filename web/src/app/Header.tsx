@@ -3,7 +3,7 @@ import { useEffect, useRef, useState } from "react";
 import { Link, useSearchParams } from "react-router-dom";
 import { discord } from "app/themes";
 
-import { useLoginMutation, useMeQuery, User } from "features/api-slice";
+import { useLoginMutation, useLogoutMutation, useMeQuery, Me, User } from "features/api-slice";
 
 const GuestHeader = () => {
 
@@ -21,7 +21,7 @@ const GuestHeader = () => {
 				<ThemeProvider theme={discord}>
 					<Button
 						variant="contained"
-						href="https://discord.com/api/oauth2/authorize?client_id=947195490322235482&redirect_uri=https%3A%2F%2Flocalhost%3A2069%2F&response_type=code&scope=identify"
+						href="/api/login/start"
 					>
 						Log in with Discord
 					</Button>
@@ -31,10 +31,22 @@ const GuestHeader = () => {
 	)
 }
 
-const UserHeader: React.FC<{ user: User }> = ({ user }) => {
+/** Discord's default avatar stands in for users who didn't upload one. */
+const avatarUrl = (user: User) => {
+	if (user.avatar !== null) {
+		return 'https://cdn.discordapp.com/avatars/' + user.id + '/' + user.avatar + '.png';
+	}
+	const index = user.discriminator && user.discriminator !== '0'
+		? Number(user.discriminator) % 5
+		: Number((BigInt(user.id) >> 22n) % 6n);
+	return 'https://cdn.discordapp.com/embed/avatars/' + index + '.png';
+}
+
+const UserHeader: React.FC<{ user: Me }> = ({ user }) => {
 
 	const [menuOpen, setMenuOpen] = useState(false);
 	const menuRef = useRef<HTMLDivElement>(null);
+	const [logout] = useLogoutMutation();
 
 	return (
 		<AppBar position="static">
@@ -47,6 +59,17 @@ const UserHeader: React.FC<{ user: User }> = ({ user }) => {
 				>
 					Euphoria
 				</Typography>
+				{user.needs_login && (
+					<ThemeProvider theme={discord}>
+						<Button
+							variant="contained"
+							href="/api/login/start"
+							sx={{ mr: 2 }}
+						>
+							Log in again
+						</Button>
+					</ThemeProvider>
+				)}
 				<Typography>
 					{user.username}
 				</Typography>
@@ -56,7 +79,7 @@ const UserHeader: React.FC<{ user: User }> = ({ user }) => {
 					<Avatar
 						ref={menuRef}
 						alt={user.username}
-						src={'https://cdn.discordapp.com/avatars/' + user.id + '/' + user.avatar + '.png'}
+						src={avatarUrl(user)}
 					/>
 				</IconButton>
 				<Menu
@@ -67,7 +90,7 @@ const UserHeader: React.FC<{ user: User }> = ({ user }) => {
 					<MenuItem component={Link} to="/settings">
 						Settings
 					</MenuItem>
-					<MenuItem>
+					<MenuItem onClick={() => logout()}>
 						Log out
 					</MenuItem>
 				</Menu>
@@ -84,11 +107,13 @@ const Header = () => {
 
 	useEffect(() => {
 		const code = searchParam.get("code")
-		if (code === null) {
+		const state = searchParam.get("state")
+		if (code === null || state === null) {
 			return;
 		}
-		login(code).then(() => {
+		login({ code, state }).then(() => {
 			searchParam.delete("code");
+			searchParam.delete("state");
 		})
 	}, [searchParam])
 
@@ -3,12 +3,20 @@ import { createApi, fetchBaseQuery } from '@reduxjs/toolkit/query/react';
 export interface User {
 	id: string,
 	username: string,
-	avatar: string,
+	global_name?: string | null,
+	discriminator?: string | null,
+	avatar: string | null,
+}
+
+/** The logged in user, as returned by `/api/me`. */
+export interface Me extends User {
+	/** The Discord token was revoked, logging in again is needed for anything using it. */
+	needs_login: boolean,
 }
 
 export type UserStatus = {
 	status: 'LoggedIn';
-	user: User;
+	user: Me;
 } | {
 	status: 'LoggedOut';
 }
@@ -22,10 +30,17 @@ export const apiSlice = createApi({
 	tagTypes: ['user'],
 	endpoints(builder) {
 		return {
-			login: builder.mutation<void, string>({
-				query: (code) => ({
+			login: builder.mutation<void, { code: string, state: string }>({
+				query: ({ code, state }) => ({
 					url: '/login',
-					params: { code },
+					params: { code, state },
+					method: 'POST',
+				}),
+				invalidatesTags: ['user'],
+			}),
+			logout: builder.mutation<void, void>({
+				query: () => ({
+					url: '/logout',
 					method: 'POST',
 				}),
 				invalidatesTags: ['user'],
@@ -43,5 +58,6 @@ export const apiSlice = createApi({
 
 export const {
 	useLoginMutation,
+	useLogoutMutation,
 	useMeQuery,
 } = apiSlice;
\ No newline at end of file